@@ -0,0 +1,129 @@
+use std::fmt::Debug;
+use std::hash::Hash;
+
+/// A memory cell of a fixed bit width, whose arithmetic wraps around at that width
+///
+/// Implemented for `u8`, `u16`, `u32` and `u64`, and sealed so it can't be
+/// implemented for anything else, as compiled programs rely on
+/// [`Cell::BITS`] being exactly the size of a cell in memory. Every conversion
+/// from a `u64` keeps only the low [`Cell::BITS`] bits, so counts can be
+/// accumulated as wrapping `u64`s and are still correct modulo the cell's width.
+pub trait Cell:
+    sealed::Sealed + Copy + Default + Eq + Hash + Debug + Send + Sync + 'static
+{
+    /// Width of the cell in bits
+    const BITS: u32;
+
+    /// The value `v` modulo 2^[`Cell::BITS`]
+    fn from_u64(v: u64) -> Self;
+
+    /// The value of the cell, zero extended
+    fn to_u64(self) -> u64;
+
+    fn wrapping_add(self, rhs: Self) -> Self;
+
+    fn is_zero(self) -> bool {
+        self == Self::default()
+    }
+
+    /// Number of strides from the start of `cells` to the first zero cell,
+    /// only looking at every `stride`th cell
+    fn find_zero(cells: &[Self], stride: usize) -> Option<usize> {
+        cells.iter().step_by(stride).position(|cell| cell.is_zero())
+    }
+
+    /// Number of strides back from the end of `cells` to the last zero cell,
+    /// only looking at every `stride`th cell counting from the end
+    fn rfind_zero(cells: &[Self], stride: usize) -> Option<usize> {
+        cells
+            .iter()
+            .rev()
+            .step_by(stride)
+            .position(|cell| cell.is_zero())
+    }
+}
+
+/// The width of some [`Cell`] type, for choosing between them at run time
+///
+/// ```
+/// use stupidfuck::CellWidth;
+///
+/// assert_eq!(CellWidth::of::<u16>().bits(), 16);
+/// assert_eq!(CellWidth::new(32), Some(CellWidth::of::<u32>()));
+/// assert_eq!(CellWidth::new(12), None);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CellWidth(u32);
+
+impl CellWidth {
+    /// The width of cells of type `C`
+    pub const fn of<C: Cell>() -> Self {
+        CellWidth(C::BITS)
+    }
+
+    /// The width of the cells `bits` wide, if there are any
+    pub const fn new(bits: u32) -> Option<Self> {
+        match bits {
+            8 | 16 | 32 | 64 => Some(CellWidth(bits)),
+            _ => None,
+        }
+    }
+
+    /// Number of bits in the cell, as in [`Cell::BITS`]
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+}
+
+mod sealed {
+    /// Supertrait of [`Cell`](super::Cell) that only this crate can implement
+    pub trait Sealed {}
+}
+
+macro_rules! impl_cell {
+    ($t:ty $(, $extra:item)*) => {
+        impl sealed::Sealed for $t {}
+
+        impl Cell for $t {
+            const BITS: u32 = <$t>::BITS;
+
+            fn from_u64(v: u64) -> Self {
+                v as $t
+            }
+
+            fn to_u64(self) -> u64 {
+                self as u64
+            }
+
+            fn wrapping_add(self, rhs: Self) -> Self {
+                <$t>::wrapping_add(self, rhs)
+            }
+
+            $($extra)*
+        }
+    };
+}
+
+impl_cell!(
+    u8,
+    // byte cells are searched a whole word at a time
+    fn find_zero(cells: &[u8], stride: usize) -> Option<usize> {
+        if stride == 1 {
+            return memchr::memchr(0, cells);
+        }
+        cells.iter().step_by(stride).position(|&cell| cell == 0)
+    },
+    fn rfind_zero(cells: &[u8], stride: usize) -> Option<usize> {
+        if stride == 1 {
+            return memchr::memrchr(0, cells).map(|index| cells.len() - 1 - index);
+        }
+        cells
+            .iter()
+            .rev()
+            .step_by(stride)
+            .position(|&cell| cell == 0)
+    }
+);
+impl_cell!(u16);
+impl_cell!(u32);
+impl_cell!(u64);
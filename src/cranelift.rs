@@ -0,0 +1,1046 @@
+//! A backend compiling a [`Program`] with [Cranelift](https://cranelift.dev),
+//! for any platform it supports. Only built with the `cranelift` feature.
+//!
+//! [`Compiled`] turns a program into machine code in memory and runs it like
+//! the x86-64 JIT does, calling back into Rust for everything but arithmetic
+//! on the tape.
+//! [`object`] puts the same code in an object file instead, along with a
+//! `main` function and a runtime built on the C library, so it can be linked
+//! into a standalone executable with e.g. `cc prog.o -o prog`. Like the
+//! programs from [`emit`](crate::emit), those report errors on stderr and
+//! exit with status 1.
+
+use std::fmt;
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{
+    types, AbiParam, Block, FuncRef, InstBuilder, MemFlags, Signature, StackSlotData,
+    StackSlotKind, TrapCode, Type, Value,
+};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, DataDescription, DataId, FuncId, Linkage, Module};
+use cranelift_object::{ObjectBuilder, ObjectModule};
+
+use crate::cell::Cell;
+use crate::error::RuntimeError;
+use crate::machine::{Config, EofPolicy, OutputMode, UnderflowPolicy};
+use crate::program::{Prelude, Program};
+use crate::runtime::{self, CELLS, LEN, MEMPTR};
+use crate::token::{Span, Token};
+
+/// Why a program couldn't be compiled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompileError(String);
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/// Turn any of Cranelift's errors into a [`CompileError`]
+fn error(err: impl fmt::Display) -> CompileError {
+    CompileError(err.to_string())
+}
+
+/// A program compiled by Cranelift for cells of type `C`, ready to be run any
+/// number of times
+///
+/// Running it behaves exactly like running the program on a fresh
+/// [`Machine`](crate::Machine) with the same [`Config`]:
+///
+/// ```
+/// use stupidfuck::cranelift::Compiled;
+/// use stupidfuck::{Config, Program};
+///
+/// let mut program = Program::parse(b",[.,]")?;
+/// program.optimize();
+/// let compiled = Compiled::<u8>::compile(&program, Config::default())?;
+/// let mut output = Vec::new();
+/// compiled.run(&b"echo"[..], &mut output)?;
+/// assert_eq!(output, b"echo");
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub struct Compiled<C: Cell = u8> {
+    /// Owner of the machine code, only taken to free it
+    module: Option<JITModule>,
+    entry: runtime::Entry,
+    /// Span of each instruction, for errors
+    spans: Vec<Span>,
+    prelude: Prelude,
+    config: Config,
+    cell: PhantomData<C>,
+}
+
+impl<C: Cell> Compiled<C> {
+    /// Compile `program` for a machine with the given [`Config`]
+    pub fn compile(program: &Program, config: Config) -> Result<Self, CompileError> {
+        const { assert!(size_of::<C>() * 8 == C::BITS as usize) };
+        let mut builder =
+            JITBuilder::with_flags(&[("opt_level", "speed")], default_libcall_names())
+                .map_err(error)?;
+        builder.symbol("stupidfuck_reach", runtime::reach::<C> as *const u8);
+        builder.symbol("stupidfuck_input", runtime::input::<C> as *const u8);
+        builder.symbol("stupidfuck_output", runtime::output::<C> as *const u8);
+        let mut module = JITModule::new(builder);
+        let ptr = module.target_config().pointer_type();
+        let callbacks = Callbacks {
+            reach: declare(
+                &mut module,
+                "stupidfuck_reach",
+                Linkage::Import,
+                &[ptr, ptr, ptr],
+                &[types::I64],
+            )?,
+            input: declare(
+                &mut module,
+                "stupidfuck_input",
+                Linkage::Import,
+                &[ptr, ptr],
+                &[types::I64],
+            )?,
+            output: declare(
+                &mut module,
+                "stupidfuck_output",
+                Linkage::Import,
+                &[ptr, types::I64, ptr],
+                &[types::I64],
+            )?,
+        };
+        let id = define_program(
+            &mut module,
+            program,
+            cell_type(C::BITS)?,
+            config,
+            &callbacks,
+            false,
+        )?;
+        module.finalize_definitions().map_err(error)?;
+        // SAFETY: the function was declared with the signature of an `Entry`
+        let entry = unsafe {
+            std::mem::transmute::<*const u8, runtime::Entry>(module.get_finalized_function(id))
+        };
+        Ok(Compiled {
+            module: Some(module),
+            entry,
+            spans: program
+                .instructions()
+                .iter()
+                .map(|inst| inst.span)
+                .collect(),
+            prelude: program.prelude().clone(),
+            config,
+            cell: PhantomData,
+        })
+    }
+
+    /// Run the program on a fresh tape, feeding `input` to ',' and writing
+    /// whatever '.' produces to `output`, flushing it like
+    /// [`Machine::run`](crate::Machine::run)
+    pub fn run<R: Read, W: Write>(&self, input: R, output: W) -> Result<(), RuntimeError> {
+        // SAFETY: the code was compiled for cells of type `C`
+        unsafe {
+            runtime::run::<C, R, W>(
+                self.entry,
+                &self.spans,
+                &self.prelude,
+                self.config,
+                input,
+                output,
+            )
+        }
+    }
+}
+
+impl<C: Cell> Drop for Compiled<C> {
+    fn drop(&mut self) {
+        if let Some(module) = self.module.take() {
+            // SAFETY: nothing can be running the code anymore
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+/// Compile `program` for cells `cell_bits` wide into an object file for the
+/// machine it's running on, defining a `main` function that runs the program
+/// on stdin and stdout
+///
+/// Fails if `cell_bits` isn't the width of a [`Cell`], i.e. 8, 16, 32 or 64.
+///
+/// ```
+/// use stupidfuck::{cranelift, Config, Program};
+///
+/// let program = Program::parse(b"+[,.]")?;
+/// let object = cranelift::object(&program, u8::BITS, &Config::default())?;
+/// assert!(!object.is_empty());
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn object(program: &Program, cell_bits: u32, config: &Config) -> Result<Vec<u8>, CompileError> {
+    let mut flags = settings::builder();
+    flags.set("opt_level", "speed").map_err(error)?;
+    // executables are usually position independent
+    flags.set("is_pic", "true").map_err(error)?;
+    let isa = cranelift_native::builder()
+        .map_err(error)?
+        .finish(settings::Flags::new(flags))
+        .map_err(error)?;
+    let builder = ObjectBuilder::new(isa, "stupidfuck", default_libcall_names()).map_err(error)?;
+    let mut module = ObjectModule::new(builder);
+    let cell = cell_type(cell_bits)?;
+    let libc = Libc::declare(&mut module)?;
+    let callbacks = define_runtime(&mut module, &libc, cell, config)?;
+    let program = define_program(&mut module, program, cell, *config, &callbacks, true)?;
+    define_main(&mut module, &libc, program, cell, config)?;
+    module.finish().emit().map_err(error)
+}
+
+/// The Cranelift type of a cell `bits` wide, for the widths a [`Cell`] can have
+fn cell_type(bits: u32) -> Result<Type, CompileError> {
+    match bits {
+        8 => Ok(types::I8),
+        16 => Ok(types::I16),
+        32 => Ok(types::I32),
+        64 => Ok(types::I64),
+        _ => Err(CompileError(format!("cells can't be {bits} bits wide"))),
+    }
+}
+
+/// Declare a function in `module` taking and returning values of the given types
+fn declare<M: Module>(
+    module: &mut M,
+    name: &str,
+    linkage: Linkage,
+    params: &[Type],
+    returns: &[Type],
+) -> Result<FuncId, CompileError> {
+    let mut sig = module.make_signature();
+    sig.params
+        .extend(params.iter().map(|&ty| AbiParam::new(ty)));
+    sig.returns
+        .extend(returns.iter().map(|&ty| AbiParam::new(ty)));
+    module.declare_function(name, linkage, &sig).map_err(error)
+}
+
+/// Build the body of the function `id` with `build`, and define it in `module`
+fn define<M: Module>(
+    module: &mut M,
+    id: FuncId,
+    build: impl FnOnce(&mut M, &mut FunctionBuilder),
+) -> Result<(), CompileError> {
+    let mut ctx = module.make_context();
+    ctx.func.signature = signature(module, id);
+    let mut builder_ctx = FunctionBuilderContext::new();
+    let mut builder = FunctionBuilder::new(&mut ctx.func, &mut builder_ctx);
+    build(module, &mut builder);
+    builder.seal_all_blocks();
+    builder.finalize();
+    module.define_function(id, &mut ctx).map_err(error)?;
+    module.clear_context(&mut ctx);
+    Ok(())
+}
+
+/// The signature `id` was declared with
+fn signature<M: Module>(module: &M, id: FuncId) -> Signature {
+    module
+        .declarations()
+        .get_function_decl(id)
+        .signature
+        .clone()
+}
+
+/// The callbacks a compiled program uses, see [`runtime`](crate::runtime)
+struct Callbacks {
+    /// `reach(state, offset, pc)`
+    reach: FuncId,
+    /// `input(state, pc)`
+    input: FuncId,
+    /// `output(state, value, pc)`
+    output: FuncId,
+}
+
+/// Define the function running `program`, which takes a pointer to its state
+/// and returns nonzero if it failed, like a [`runtime::Entry`]
+///
+/// The prelude is only run by the function itself if `prelude` is set.
+fn define_program<M: Module>(
+    module: &mut M,
+    program: &Program,
+    cell: Type,
+    config: Config,
+    callbacks: &Callbacks,
+    prelude: bool,
+) -> Result<FuncId, CompileError> {
+    let ptr = module.target_config().pointer_type();
+    let id = declare(
+        module,
+        "stupidfuck_program",
+        Linkage::Local,
+        &[ptr],
+        &[types::I64],
+    )?;
+    define(module, id, |module, builder| {
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        let state = builder.block_params(entry)[0];
+        let fail = builder.create_block();
+        builder.append_block_param(fail, types::I64);
+        let mut t = Translator {
+            reach: module.declare_func_in_func(callbacks.reach, builder.func),
+            input: module.declare_func_in_func(callbacks.input, builder.func),
+            output: module.declare_func_in_func(callbacks.output, builder.func),
+            builder,
+            ptr,
+            cell,
+            config,
+            state,
+            fail,
+            cells: Variable::from_u32(0),
+            memptr: Variable::from_u32(1),
+            len: Variable::from_u32(2),
+        };
+        t.declare_vars();
+        if prelude {
+            t.prelude(program.prelude());
+        }
+        t.instructions(program);
+        let memptr = t.builder.use_var(t.memptr);
+        t.builder
+            .ins()
+            .store(MemFlags::trusted(), memptr, state, i32::from(MEMPTR));
+        let zero = t.builder.ins().iconst(types::I64, 0);
+        t.builder.ins().return_(&[zero]);
+        t.builder.switch_to_block(fail);
+        let failed = t.builder.block_params(fail)[0];
+        t.builder.ins().return_(&[failed]);
+    })?;
+    Ok(id)
+}
+
+/// Translates instructions into Cranelift IR
+struct Translator<'a, 'b> {
+    builder: &'a mut FunctionBuilder<'b>,
+    ptr: Type,
+    cell: Type,
+    config: Config,
+    /// Pointer to the state
+    state: Value,
+    /// Block returning its parameter, for when a callback fails
+    fail: Block,
+    /// Variables holding the fields of the state
+    cells: Variable,
+    memptr: Variable,
+    len: Variable,
+    reach: FuncRef,
+    input: FuncRef,
+    output: FuncRef,
+}
+
+impl Translator<'_, '_> {
+    fn declare_vars(&mut self) {
+        self.builder.declare_var(self.cells, self.ptr);
+        self.builder.declare_var(self.memptr, self.ptr);
+        self.builder.declare_var(self.len, self.ptr);
+        self.reload();
+    }
+
+    /// Read the tape's address, the data pointer and the tape's length from
+    /// the state
+    fn reload(&mut self) {
+        for (var, offset) in [(self.cells, CELLS), (self.memptr, MEMPTR), (self.len, LEN)] {
+            let value = self.builder.ins().load(
+                self.ptr,
+                MemFlags::trusted(),
+                self.state,
+                i32::from(offset),
+            );
+            self.builder.def_var(var, value);
+        }
+    }
+
+    /// Set up the tape as `prelude` left it and write out its output
+    fn prelude(&mut self, prelude: &Prelude) {
+        for (number, &value) in prelude.tape.iter().enumerate() {
+            let index = self.index(number as isize, 0);
+            let value = self.cell_const(value);
+            self.store(index, value);
+        }
+        for &value in &prelude.output {
+            let value = self.builder.ins().iconst(types::I64, value as i64);
+            let pc = self.builder.ins().iconst(self.ptr, 0);
+            self.call(self.output, &[self.state, value, pc]);
+        }
+        let memptr = self.index(prelude.memptr as isize, 0);
+        self.builder.def_var(self.memptr, memptr);
+    }
+
+    fn instructions(&mut self, program: &Program) {
+        // header and exit of each loop we're in
+        let mut loops = Vec::new();
+        for (pc, inst) in program.instructions().iter().enumerate() {
+            match inst.token {
+                Token::Move(amount) => {
+                    let index = self.index(amount, pc);
+                    self.builder.def_var(self.memptr, index);
+                }
+                Token::Add { offset, value } => {
+                    let index = self.index(offset, pc);
+                    let old = self.load(index);
+                    let value = self.cell_const(value);
+                    let new = self.builder.ins().iadd(old, value);
+                    self.store(index, new);
+                }
+                Token::Set(value) => {
+                    let memptr = self.builder.use_var(self.memptr);
+                    let value = self.cell_const(value);
+                    self.store(memptr, value);
+                }
+                Token::MulAdd { offset, factor } => {
+                    let body = self.builder.create_block();
+                    let next = self.builder.create_block();
+                    let current = self.current();
+                    self.builder.ins().brif(current, body, &[], next, &[]);
+                    self.builder.switch_to_block(body);
+                    let index = self.index(offset, pc);
+                    // reaching the cell may have moved the tape
+                    let current = self.current();
+                    let factor = self.cell_const(factor);
+                    let product = self.builder.ins().imul(current, factor);
+                    let old = self.load(index);
+                    let new = self.builder.ins().iadd(old, product);
+                    self.store(index, new);
+                    self.builder.ins().jump(next, &[]);
+                    self.builder.switch_to_block(next);
+                }
+                Token::ScanRight(stride) => self.scan(stride as isize, pc),
+                Token::ScanLeft(stride) => self.scan(-(stride as isize), pc),
+                Token::Open(_) => {
+                    let header = self.builder.create_block();
+                    let body = self.builder.create_block();
+                    let exit = self.builder.create_block();
+                    self.builder.ins().jump(header, &[]);
+                    self.builder.switch_to_block(header);
+                    let current = self.current();
+                    self.builder.ins().brif(current, body, &[], exit, &[]);
+                    self.builder.switch_to_block(body);
+                    loops.push((header, exit));
+                }
+                Token::Close(_) => {
+                    let (header, exit) = loops.pop().expect("brackets are matched");
+                    self.builder.ins().jump(header, &[]);
+                    self.builder.switch_to_block(exit);
+                }
+                Token::Input => {
+                    let pc = self.builder.ins().iconst(self.ptr, pc as i64);
+                    self.call(self.input, &[self.state, pc]);
+                }
+                Token::Output => {
+                    let current = self.current();
+                    let value = if self.cell == types::I64 {
+                        current
+                    } else {
+                        self.builder.ins().uextend(types::I64, current)
+                    };
+                    let pc = self.builder.ins().iconst(self.ptr, pc as i64);
+                    self.call(self.output, &[self.state, value, pc]);
+                }
+            }
+        }
+    }
+
+    /// Move the data pointer `stride` cells at a time until it's on a zero
+    fn scan(&mut self, stride: isize, pc: usize) {
+        let header = self.builder.create_block();
+        let step = self.builder.create_block();
+        let exit = self.builder.create_block();
+        self.builder.ins().jump(header, &[]);
+        self.builder.switch_to_block(header);
+        let current = self.current();
+        self.builder.ins().brif(current, step, &[], exit, &[]);
+        self.builder.switch_to_block(step);
+        let index = self.index(stride, pc);
+        self.builder.def_var(self.memptr, index);
+        self.builder.ins().jump(header, &[]);
+        self.builder.switch_to_block(exit);
+    }
+
+    /// Index of the cell `offset` cells from the data pointer, reaching it
+    /// through the runtime if it isn't on the tape yet
+    fn index(&mut self, offset: isize, pc: usize) -> Value {
+        let memptr = self.builder.use_var(self.memptr);
+        if offset == 0 {
+            return memptr;
+        }
+        let len = self.builder.use_var(self.len);
+        if self.config.underflow == UnderflowPolicy::Wrap {
+            let tape_len = self.config.tape_len.max(1) as isize;
+            let index = self
+                .builder
+                .ins()
+                .iadd_imm(memptr, offset.rem_euclid(tape_len) as i64);
+            let wrapped = self.builder.ins().isub(index, len);
+            let past_end = self
+                .builder
+                .ins()
+                .icmp(IntCC::UnsignedGreaterThanOrEqual, index, len);
+            return self.builder.ins().select(past_end, wrapped, index);
+        }
+        let index = self.builder.ins().iadd_imm(memptr, offset as i64);
+        // negative indices are past the end as unsigned numbers too
+        let on_tape = self.builder.ins().icmp(IntCC::UnsignedLessThan, index, len);
+        let reach = self.builder.create_block();
+        let done = self.builder.create_block();
+        self.builder.append_block_param(done, self.ptr);
+        self.builder.ins().brif(on_tape, done, &[index], reach, &[]);
+        self.builder.switch_to_block(reach);
+        let offset_arg = self.builder.ins().iconst(self.ptr, offset as i64);
+        let pc = self.builder.ins().iconst(self.ptr, pc as i64);
+        self.call(self.reach, &[self.state, offset_arg, pc]);
+        let memptr = self.builder.use_var(self.memptr);
+        let index = self.builder.ins().iadd_imm(memptr, offset as i64);
+        self.builder.ins().jump(done, &[index]);
+        self.builder.switch_to_block(done);
+        self.builder.block_params(done)[0]
+    }
+
+    /// Call a callback, then pick up the tape again and leave if it failed
+    fn call(&mut self, callback: FuncRef, args: &[Value]) {
+        let memptr = self.builder.use_var(self.memptr);
+        self.builder
+            .ins()
+            .store(MemFlags::trusted(), memptr, self.state, i32::from(MEMPTR));
+        let call = self.builder.ins().call(callback, args);
+        let failed = self.builder.inst_results(call)[0];
+        self.reload();
+        let next = self.builder.create_block();
+        self.builder
+            .ins()
+            .brif(failed, self.fail, &[failed], next, &[]);
+        self.builder.switch_to_block(next);
+    }
+
+    /// Address of the cell at `index`
+    fn address(&mut self, index: Value) -> Value {
+        let cells = self.builder.use_var(self.cells);
+        let offset = self
+            .builder
+            .ins()
+            .imul_imm(index, i64::from(self.cell.bytes()));
+        self.builder.ins().iadd(cells, offset)
+    }
+
+    fn load(&mut self, index: Value) -> Value {
+        let address = self.address(index);
+        self.builder
+            .ins()
+            .load(self.cell, MemFlags::trusted(), address, 0)
+    }
+
+    fn store(&mut self, index: Value, value: Value) {
+        let address = self.address(index);
+        self.builder
+            .ins()
+            .store(MemFlags::trusted(), value, address, 0);
+    }
+
+    /// The value of the cell under the data pointer
+    fn current(&mut self) -> Value {
+        let memptr = self.builder.use_var(self.memptr);
+        self.load(memptr)
+    }
+
+    /// `value` as a cell, keeping only as many bits as fit
+    fn cell_const(&mut self, value: u64) -> Value {
+        let mask = u64::MAX >> (64 - self.cell.bits());
+        self.builder.ins().iconst(self.cell, (value & mask) as i64)
+    }
+}
+
+/// Functions from the C library used by the runtime of object files
+struct Libc {
+    calloc: FuncId,
+    realloc: FuncId,
+    memset: FuncId,
+    memcpy: FuncId,
+    free: FuncId,
+    abort: FuncId,
+    exit: FuncId,
+    fflush: FuncId,
+    getchar: FuncId,
+    putchar: FuncId,
+    write: FuncId,
+}
+
+/// [`Libc`] as declared in a single function
+struct LibcRefs {
+    calloc: FuncRef,
+    realloc: FuncRef,
+    memset: FuncRef,
+    memcpy: FuncRef,
+    free: FuncRef,
+    abort: FuncRef,
+    exit: FuncRef,
+    fflush: FuncRef,
+    getchar: FuncRef,
+    putchar: FuncRef,
+    write: FuncRef,
+}
+
+impl Libc {
+    fn declare<M: Module>(module: &mut M) -> Result<Self, CompileError> {
+        let ptr = module.target_config().pointer_type();
+        let int = types::I32;
+        let mut import = |name, params: &[Type], returns: &[Type]| {
+            declare(module, name, Linkage::Import, params, returns)
+        };
+        Ok(Libc {
+            calloc: import("calloc", &[ptr, ptr], &[ptr])?,
+            realloc: import("realloc", &[ptr, ptr], &[ptr])?,
+            memset: import("memset", &[ptr, int, ptr], &[ptr])?,
+            memcpy: import("memcpy", &[ptr, ptr, ptr], &[ptr])?,
+            free: import("free", &[ptr], &[])?,
+            abort: import("abort", &[], &[])?,
+            exit: import("exit", &[int], &[])?,
+            fflush: import("fflush", &[ptr], &[int])?,
+            getchar: import("getchar", &[], &[int])?,
+            putchar: import("putchar", &[int], &[int])?,
+            write: import("write", &[int, ptr, ptr], &[ptr])?,
+        })
+    }
+
+    /// Declare every function in the function `builder` is building
+    fn refs<M: Module>(&self, module: &mut M, builder: &mut FunctionBuilder) -> LibcRefs {
+        let mut declare = |id| module.declare_func_in_func(id, builder.func);
+        LibcRefs {
+            calloc: declare(self.calloc),
+            realloc: declare(self.realloc),
+            memset: declare(self.memset),
+            memcpy: declare(self.memcpy),
+            free: declare(self.free),
+            abort: declare(self.abort),
+            exit: declare(self.exit),
+            fflush: declare(self.fflush),
+            getchar: declare(self.getchar),
+            putchar: declare(self.putchar),
+            write: declare(self.write),
+        }
+    }
+}
+
+/// Call `func`, returning its result if it has one
+fn call(builder: &mut FunctionBuilder, func: FuncRef, args: &[Value]) -> Option<Value> {
+    let call = builder.ins().call(func, args);
+    builder.inst_results(call).first().copied()
+}
+
+/// Stop the program if `pointer` is null, as allocating it failed
+fn check_alloc(builder: &mut FunctionBuilder, libc: &LibcRefs, pointer: Value) {
+    let failed = builder.create_block();
+    let next = builder.create_block();
+    builder.ins().brif(pointer, next, &[], failed, &[]);
+    builder.switch_to_block(failed);
+    call(builder, libc.abort, &[]);
+    builder.ins().trap(TrapCode::unwrap_user(1));
+    builder.switch_to_block(next);
+}
+
+/// Start of an error message in an object file
+struct Message {
+    id: DataId,
+    len: usize,
+}
+
+impl Message {
+    fn define<M: Module>(module: &mut M, name: &str, text: &str) -> Result<Self, CompileError> {
+        let id = module
+            .declare_data(name, Linkage::Local, false, false)
+            .map_err(error)?;
+        let mut data = DataDescription::new();
+        data.define(text.as_bytes().into());
+        module.define_data(id, &data).map_err(error)?;
+        Ok(Message {
+            id,
+            len: text.len(),
+        })
+    }
+
+    /// Report the error at instruction `pc` and exit, from the function
+    /// `builder` is building
+    fn fail<M: Module>(
+        &self,
+        module: &mut M,
+        builder: &mut FunctionBuilder,
+        fail: FuncId,
+        pc: Value,
+    ) {
+        let ptr = module.target_config().pointer_type();
+        let data = module.declare_data_in_func(self.id, builder.func);
+        let text = builder.ins().symbol_value(ptr, data);
+        let len = builder.ins().iconst(ptr, self.len as i64);
+        let fail = module.declare_func_in_func(fail, builder.func);
+        builder.ins().call(fail, &[text, len, pc]);
+        builder.ins().trap(TrapCode::unwrap_user(1));
+    }
+}
+
+/// Define the callbacks for object files, see [`runtime`](crate::runtime),
+/// which use stdin and stdout and exit the whole program if it fails
+fn define_runtime<M: Module>(
+    module: &mut M,
+    libc: &Libc,
+    cell: Type,
+    config: &Config,
+) -> Result<Callbacks, CompileError> {
+    let ptr = module.target_config().pointer_type();
+    let fail = define_fail(module, libc)?;
+    let size = i64::from(cell.bytes());
+
+    let reach = declare(
+        module,
+        "stupidfuck_reach",
+        Linkage::Local,
+        &[ptr, ptr, ptr],
+        &[types::I64],
+    )?;
+    let underflow = Message::define(module, "underflow", "error: tape underflow at instruction ")?;
+    define(module, reach, |module, builder| {
+        let libc = libc.refs(module, builder);
+        let [state, offset, pc] = params(builder);
+        let memptr = load_field(builder, ptr, state, MEMPTR);
+        let index = builder.ins().iadd(memptr, offset);
+        let left = builder.create_block();
+        let right = builder.create_block();
+        builder.append_block_param(right, ptr);
+        let off_start = builder.ins().icmp_imm(IntCC::SignedLessThan, index, 0);
+        builder.ins().brif(off_start, left, &[], right, &[index]);
+
+        builder.switch_to_block(left);
+        if config.underflow == UnderflowPolicy::Grow {
+            // add at least as many cells as the tape has, like `Tape::grow_left`
+            let len = load_field(builder, ptr, state, LEN);
+            let cells = load_field(builder, ptr, state, CELLS);
+            let missing = builder.ins().ineg(index);
+            let added = builder.ins().umax(missing, len);
+            let new_len = builder.ins().iadd(len, added);
+            let size = builder.ins().iconst(ptr, size);
+            let new_cells = call(builder, libc.calloc, &[new_len, size]).unwrap();
+            check_alloc(builder, &libc, new_cells);
+            let skipped = builder.ins().imul(added, size);
+            let dest = builder.ins().iadd(new_cells, skipped);
+            let bytes = builder.ins().imul(len, size);
+            call(builder, libc.memcpy, &[dest, cells, bytes]);
+            call(builder, libc.free, &[cells]);
+            let memptr = builder.ins().iadd(memptr, added);
+            store_field(builder, state, CELLS, new_cells);
+            store_field(builder, state, LEN, new_len);
+            store_field(builder, state, MEMPTR, memptr);
+            let index = builder.ins().iadd(memptr, offset);
+            builder.ins().jump(right, &[index]);
+        } else {
+            underflow.fail(module, builder, fail, pc);
+        }
+
+        builder.switch_to_block(right);
+        let index = builder.block_params(right)[0];
+        let len = load_field(builder, ptr, state, LEN);
+        let on_tape = builder.ins().icmp(IntCC::UnsignedLessThan, index, len);
+        let grow = builder.create_block();
+        let done = builder.create_block();
+        builder.ins().brif(on_tape, done, &[], grow, &[]);
+        builder.switch_to_block(grow);
+        // at least double the tape, so walking right only copies it O(log n) times
+        let doubled = builder.ins().ishl_imm(len, 1);
+        let needed = builder.ins().iadd_imm(index, 1);
+        let new_len = builder.ins().umax(doubled, needed);
+        let cells = load_field(builder, ptr, state, CELLS);
+        let bytes = builder.ins().imul_imm(new_len, size);
+        let new_cells = call(builder, libc.realloc, &[cells, bytes]).unwrap();
+        check_alloc(builder, &libc, new_cells);
+        let old_bytes = builder.ins().imul_imm(len, size);
+        let start = builder.ins().iadd(new_cells, old_bytes);
+        let added = builder.ins().isub(bytes, old_bytes);
+        let zero = builder.ins().iconst(types::I32, 0);
+        call(builder, libc.memset, &[start, zero, added]);
+        store_field(builder, state, CELLS, new_cells);
+        store_field(builder, state, LEN, new_len);
+        builder.ins().jump(done, &[]);
+        builder.switch_to_block(done);
+        return_ok(builder);
+    })?;
+
+    let input = declare(
+        module,
+        "stupidfuck_input",
+        Linkage::Local,
+        &[ptr, ptr],
+        &[types::I64],
+    )?;
+    let eof = Message::define(
+        module,
+        "eof",
+        "error: unexpected end of input at instruction ",
+    )?;
+    define(module, input, |module, builder| {
+        let libc = libc.refs(module, builder);
+        let [state, pc] = params(builder);
+        let null = builder.ins().iconst(ptr, 0);
+        call(builder, libc.fflush, &[null]);
+        let byte = call(builder, libc.getchar, &[]).unwrap();
+        let read = builder.create_block();
+        let at_eof = builder.create_block();
+        let is_eof = builder.ins().icmp_imm(IntCC::SignedLessThan, byte, 0);
+        builder.ins().brif(is_eof, at_eof, &[], read, &[]);
+
+        builder.switch_to_block(read);
+        let value = match cell.bits() {
+            8 | 16 => builder.ins().ireduce(cell, byte),
+            32 => byte,
+            _ => builder.ins().uextend(cell, byte),
+        };
+        store_current(builder, ptr, cell, state, value);
+        return_ok(builder);
+
+        builder.switch_to_block(at_eof);
+        let mask = u64::MAX >> (64 - cell.bits());
+        match config.eof {
+            EofPolicy::Zero => {
+                let zero = builder.ins().iconst(cell, 0);
+                store_current(builder, ptr, cell, state, zero);
+                return_ok(builder);
+            }
+            EofPolicy::MinusOne => {
+                let minus_one = builder.ins().iconst(cell, mask as i64);
+                store_current(builder, ptr, cell, state, minus_one);
+                return_ok(builder);
+            }
+            EofPolicy::Unchanged => return_ok(builder),
+            EofPolicy::Error => eof.fail(module, builder, fail, pc),
+        }
+    })?;
+
+    let output = declare(
+        module,
+        "stupidfuck_output",
+        Linkage::Local,
+        &[ptr, types::I64, ptr],
+        &[types::I64],
+    )?;
+    define(module, output, |module, builder| {
+        let libc = libc.refs(module, builder);
+        let [_, value, _] = params(builder);
+        let put = |builder: &mut FunctionBuilder, byte: Value| {
+            let byte = builder.ins().ireduce(types::I32, byte);
+            call(builder, libc.putchar, &[byte]);
+        };
+        match config.output {
+            OutputMode::Bytes => put(builder, value),
+            OutputMode::Text => {
+                // anything that isn't a code point comes out as U+FFFD
+                let in_range =
+                    builder
+                        .ins()
+                        .icmp_imm(IntCC::UnsignedLessThanOrEqual, value, 0x10FFFF);
+                let high = builder
+                    .ins()
+                    .icmp_imm(IntCC::UnsignedGreaterThanOrEqual, value, 0xD800);
+                let low = builder
+                    .ins()
+                    .icmp_imm(IntCC::UnsignedLessThanOrEqual, value, 0xDFFF);
+                let surrogate = builder.ins().band(high, low);
+                let not_surrogate = builder.ins().icmp_imm(IntCC::Equal, surrogate, 0);
+                let valid = builder.ins().band(in_range, not_surrogate);
+                let replacement = builder.ins().iconst(types::I64, 0xFFFD);
+                let c = builder.ins().select(valid, value, replacement);
+
+                let done = builder.create_block();
+                let mut rest = builder.create_block();
+                // encodings of 1 to 4 bytes, each for code points below `limit`
+                for (bytes, limit) in [(1, 0x80), (2, 0x800), (3, 0x10000), (4, 0)] {
+                    let encode = builder.create_block();
+                    if limit != 0 {
+                        let fits = builder.ins().icmp_imm(IntCC::UnsignedLessThan, c, limit);
+                        builder.ins().brif(fits, encode, &[], rest, &[]);
+                    } else {
+                        builder.ins().jump(encode, &[]);
+                    }
+                    builder.switch_to_block(encode);
+                    let lead = builder.ins().ushr_imm(c, 6 * (bytes - 1));
+                    let prefix = [0, 0xC0, 0xE0, 0xF0][bytes as usize - 1];
+                    let lead = builder.ins().bor_imm(lead, prefix);
+                    put(builder, lead);
+                    for shift in (0..bytes - 1).rev() {
+                        let bits = builder.ins().ushr_imm(c, 6 * shift);
+                        let bits = builder.ins().band_imm(bits, 0x3F);
+                        let byte = builder.ins().bor_imm(bits, 0x80);
+                        put(builder, byte);
+                    }
+                    builder.ins().jump(done, &[]);
+                    builder.switch_to_block(rest);
+                    rest = builder.create_block();
+                }
+                builder.ins().jump(done, &[]);
+                builder.switch_to_block(done);
+            }
+        }
+        return_ok(builder);
+    })?;
+
+    Ok(Callbacks {
+        reach,
+        input,
+        output,
+    })
+}
+
+/// Define the function reporting an error at an instruction and exiting, which
+/// takes the start of the message and its length, and the instruction's number
+fn define_fail<M: Module>(module: &mut M, libc: &Libc) -> Result<FuncId, CompileError> {
+    let ptr = module.target_config().pointer_type();
+    let fail = declare(
+        module,
+        "stupidfuck_fail",
+        Linkage::Local,
+        &[ptr, ptr, ptr],
+        &[],
+    )?;
+    define(module, fail, |module, builder| {
+        let libc = libc.refs(module, builder);
+        let [text, len, pc] = params(builder);
+        let null = builder.ins().iconst(ptr, 0);
+        call(builder, libc.fflush, &[null]);
+        let stderr = builder.ins().iconst(types::I32, 2);
+        call(builder, libc.write, &[stderr, text, len]);
+
+        // write the number backwards from the end of a buffer, followed by a newline
+        const BUF_LEN: i64 = 24;
+        let slot = builder.create_sized_stack_slot(StackSlotData::new(
+            StackSlotKind::ExplicitSlot,
+            BUF_LEN as u32,
+            0,
+        ));
+        let buf = builder.ins().stack_addr(ptr, slot, 0);
+        let newline = builder.ins().iconst(types::I8, i64::from(b'\n'));
+        builder.ins().stack_store(newline, slot, BUF_LEN as i32 - 1);
+        let digits = builder.create_block();
+        builder.append_block_param(digits, ptr);
+        builder.append_block_param(digits, ptr);
+        let written = builder.create_block();
+        builder.append_block_param(written, ptr);
+        let end = builder.ins().iconst(ptr, BUF_LEN - 1);
+        builder.ins().jump(digits, &[end, pc]);
+
+        builder.switch_to_block(digits);
+        let [start, n] = builder.block_params(digits).try_into().unwrap();
+        let start = builder.ins().iadd_imm(start, -1);
+        let digit = builder.ins().urem_imm(n, 10);
+        let digit = builder.ins().iadd_imm(digit, i64::from(b'0'));
+        let digit = builder.ins().ireduce(types::I8, digit);
+        let at = builder.ins().iadd(buf, start);
+        builder.ins().store(MemFlags::trusted(), digit, at, 0);
+        let n = builder.ins().udiv_imm(n, 10);
+        builder
+            .ins()
+            .brif(n, digits, &[start, n], written, &[start]);
+
+        builder.switch_to_block(written);
+        let start = builder.block_params(written)[0];
+        let at = builder.ins().iadd(buf, start);
+        let buf_len = builder.ins().iconst(ptr, BUF_LEN);
+        let count = builder.ins().isub(buf_len, start);
+        call(builder, libc.write, &[stderr, at, count]);
+        let status = builder.ins().iconst(types::I32, 1);
+        call(builder, libc.exit, &[status]);
+        builder.ins().trap(TrapCode::unwrap_user(1));
+    })?;
+    Ok(fail)
+}
+
+/// Define `main`, running `program` on a fresh tape and exiting with status 1
+/// if writing its output failed
+fn define_main<M: Module>(
+    module: &mut M,
+    libc: &Libc,
+    program: FuncId,
+    cell: Type,
+    config: &Config,
+) -> Result<(), CompileError> {
+    let ptr = module.target_config().pointer_type();
+    let main = declare(module, "main", Linkage::Export, &[], &[types::I32])?;
+    define(module, main, |module, builder| {
+        let libc = libc.refs(module, builder);
+        let entry = builder.create_block();
+        builder.switch_to_block(entry);
+        let slot = builder.create_sized_stack_slot(StackSlotData::new(
+            StackSlotKind::ExplicitSlot,
+            u32::from(LEN) + ptr.bytes(),
+            3,
+        ));
+        let state = builder.ins().stack_addr(ptr, slot, 0);
+        let tape_len = match config.underflow {
+            UnderflowPolicy::Wrap => config.tape_len.max(1),
+            _ => 1,
+        };
+        let len = builder.ins().iconst(ptr, tape_len as i64);
+        let size = builder.ins().iconst(ptr, i64::from(cell.bytes()));
+        let cells = call(builder, libc.calloc, &[len, size]).unwrap();
+        check_alloc(builder, &libc, cells);
+        let memptr = builder.ins().iconst(ptr, 0);
+        store_field(builder, state, CELLS, cells);
+        store_field(builder, state, MEMPTR, memptr);
+        store_field(builder, state, LEN, len);
+        let program = module.declare_func_in_func(program, builder.func);
+        builder.ins().call(program, &[state]);
+        let null = builder.ins().iconst(ptr, 0);
+        let flushed = call(builder, libc.fflush, &[null]).unwrap();
+        let failed = builder.ins().icmp_imm(IntCC::NotEqual, flushed, 0);
+        let status = builder.ins().uextend(types::I32, failed);
+        builder.ins().return_(&[status]);
+    })?;
+    Ok(())
+}
+
+/// Start the function `builder` is building, returning its parameters
+fn params<const N: usize>(builder: &mut FunctionBuilder) -> [Value; N] {
+    let entry = builder.create_block();
+    builder.append_block_params_for_function_params(entry);
+    builder.switch_to_block(entry);
+    builder
+        .block_params(entry)
+        .try_into()
+        .expect("function takes N parameters")
+}
+
+/// Read a field of the state
+fn load_field(builder: &mut FunctionBuilder, ptr: Type, state: Value, offset: u8) -> Value {
+    builder
+        .ins()
+        .load(ptr, MemFlags::trusted(), state, i32::from(offset))
+}
+
+/// Overwrite a field of the state
+fn store_field(builder: &mut FunctionBuilder, state: Value, offset: u8, value: Value) {
+    builder
+        .ins()
+        .store(MemFlags::trusted(), value, state, i32::from(offset));
+}
+
+/// Overwrite the cell under the data pointer
+fn store_current(builder: &mut FunctionBuilder, ptr: Type, cell: Type, state: Value, value: Value) {
+    let cells = load_field(builder, ptr, state, CELLS);
+    let memptr = load_field(builder, ptr, state, MEMPTR);
+    let offset = builder.ins().imul_imm(memptr, i64::from(cell.bytes()));
+    let address = builder.ins().iadd(cells, offset);
+    builder.ins().store(MemFlags::trusted(), value, address, 0);
+}
+
+/// Return 0 from a callback, as it succeeded
+fn return_ok(builder: &mut FunctionBuilder) {
+    let ok = builder.ins().iconst(types::I64, 0);
+    builder.ins().return_(&[ok]);
+}
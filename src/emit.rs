@@ -0,0 +1,590 @@
+//! Backends turning a [`Program`] into source code for another language.
+//!
+//! The generated programs behave like a fresh [`Machine`](crate::Machine) with
+//! the same cell width and [`Config`] running the program: the tape grows,
+//! wraps or stops the program the same way, and input and output follow the
+//! [`EofPolicy`] and [`OutputMode`]. Errors are reported on stderr with the
+//! same message (but without the source snippet) and exit status 1.
+
+use std::fmt::Write;
+
+use crate::cell::Cell;
+use crate::machine::{Config, EofPolicy, OutputMode, UnderflowPolicy};
+use crate::program::Program;
+use crate::token::Token;
+
+/// Translate `program` into a standalone C program for cells of type `C`,
+/// reading from stdin and writing to stdout
+///
+/// ```
+/// use stupidfuck::{emit, Config, Program};
+///
+/// let program = Program::parse(b"+[,.]")?;
+/// let source = emit::c::<u8>(&program, &Config::default());
+/// assert!(source.contains("int main(void)"));
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn c<C: Cell>(program: &Program, config: &Config) -> String {
+    let mut out = String::new();
+    out.push_str("/* generated by stupidfuck */\n");
+    out.push_str("#include <stddef.h>\n#include <stdint.h>\n#include <stdio.h>\n");
+    out.push_str("#include <stdlib.h>\n#include <string.h>\n\n");
+    writeln!(out, "typedef uint{}_t cell;\n", C::BITS).unwrap();
+    out.push_str(C_TAPE);
+    // C compilers warn about helpers that are never called
+    let uses = Uses::of(program);
+    if (uses.cells && config.underflow == UnderflowPolicy::Error)
+        || (uses.input && config.eof == EofPolicy::Error)
+    {
+        out.push_str(C_FAIL);
+    }
+    if uses.cells && config.underflow != UnderflowPolicy::Wrap {
+        out.push_str(C_GROW_RIGHT);
+    }
+    if uses.cells && config.underflow == UnderflowPolicy::Grow {
+        out.push_str(C_GROW_LEFT);
+    }
+    if uses.cells {
+        c_at(&mut out, config.underflow);
+    }
+    if uses.output {
+        out.push_str("\n/* write a cell out */\nstatic void put(uint64_t v) {\n");
+        match config.output {
+            OutputMode::Bytes => out.push_str("    putchar((unsigned char)v);\n"),
+            OutputMode::Text => out.push_str(C_PUT_UTF8),
+        }
+        out.push_str("}\n");
+    }
+    if uses.input {
+        c_get(&mut out, config.eof);
+    }
+
+    out.push_str("\nint main(void) {\n    size_t i;\n");
+    let tape_len = match config.underflow {
+        UnderflowPolicy::Wrap => config.tape_len.max(1),
+        _ => 1,
+    };
+    writeln!(out, "    len = {tape_len};").unwrap();
+    out.push_str("    tape = calloc(len, sizeof(cell));\n");
+    out.push_str("    if (!tape) {\n        abort();\n    }\n");
+
+    let prelude = program.prelude();
+    if !prelude.tape.is_empty() {
+        writeln!(
+            out,
+            "    static const cell prelude_tape[] = {{{}}};",
+            join(&prelude.tape)
+        )
+        .unwrap();
+        out.push_str("    for (i = 0; i < sizeof prelude_tape / sizeof *prelude_tape; i++) {\n");
+        out.push_str("        size_t j = at((ptrdiff_t)i, 0);\n");
+        out.push_str("        tape[j] = prelude_tape[i];\n    }\n");
+    }
+    if !prelude.output.is_empty() {
+        writeln!(
+            out,
+            "    static const uint64_t prelude_output[] = {{{}}};",
+            join(&prelude.output)
+        )
+        .unwrap();
+        out.push_str(
+            "    for (i = 0; i < sizeof prelude_output / sizeof *prelude_output; i++) {\n",
+        );
+        out.push_str("        put(prelude_output[i]);\n    }\n");
+    }
+    if prelude.memptr != 0 {
+        writeln!(out, "    p = at({}, 0);", prelude.memptr).unwrap();
+    }
+
+    let mut depth = 1;
+    for (pc, inst) in program.instructions().iter().enumerate() {
+        if let Token::Close(_) = inst.token {
+            depth -= 1;
+        }
+        let indent = "    ".repeat(depth);
+        let line = match inst.token {
+            Token::Move(amount) => format!("p = at({amount}, {pc});"),
+            Token::Add { offset, value } => {
+                format!("i = at({offset}, {pc}); tape[i] += (cell)UINT64_C({value});")
+            }
+            Token::Set(value) => format!("tape[p] = (cell)UINT64_C({value});"),
+            Token::MulAdd { offset, factor } => format!(
+                "if (tape[p]) {{ i = at({offset}, {pc}); \
+                 tape[i] = (cell)(tape[i] + tape[p] * UINT64_C({factor})); }}"
+            ),
+            Token::ScanRight(stride) => format!("while (tape[p]) p = at({stride}, {pc});"),
+            Token::ScanLeft(stride) => format!("while (tape[p]) p = at(-{stride}, {pc});"),
+            Token::Open(_) => "while (tape[p]) {".to_string(),
+            Token::Close(_) => "}".to_string(),
+            Token::Input => format!("get({pc});"),
+            Token::Output => "put(tape[p]);".to_string(),
+        };
+        writeln!(out, "{indent}{line}").unwrap();
+        if let Token::Open(_) = inst.token {
+            depth += 1;
+        }
+    }
+    out.push_str("    (void)i;\n    return fflush(stdout) != 0 || ferror(stdout);\n}\n");
+    out
+}
+
+/// Translate `program` into a standalone Rust program for cells of type `C`,
+/// reading from stdin and writing to stdout
+///
+/// This is [`rust_module`] with a `main` function calling its `run`.
+///
+/// ```
+/// use stupidfuck::{emit, Config, Program};
+///
+/// let program = Program::parse(b"+[,.]")?;
+/// let source = emit::rust::<u8>(&program, &Config::default());
+/// assert!(source.contains("fn main()"));
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn rust<C: Cell>(program: &Program, config: &Config) -> String {
+    let mut out = rust_module::<C>(program, config);
+    out.push_str(RUST_MAIN);
+    out
+}
+
+/// Translate `program` into Rust source for a module with cells of type `C`,
+/// to be vendored into another crate
+///
+/// The module has a `pub fn run(input: &mut impl Read, output: &mut impl Write)`
+/// running the program on a fresh tape, which returns the module's `Error`
+/// if the program fails.
+pub fn rust_module<C: Cell>(program: &Program, config: &Config) -> String {
+    let uses = Uses::of(program);
+    let underflow = uses.cells && config.underflow == UnderflowPolicy::Error;
+    let eof = uses.input && config.eof == EofPolicy::Error;
+    let mut out = String::new();
+    out.push_str("// generated by stupidfuck\n\n");
+    out.push_str("use std::fmt;\nuse std::io::{self, Read, Write};\n\n");
+    writeln!(out, "type Cell = u{};\n", C::BITS).unwrap();
+
+    out.push_str("/// Why the program stopped early\n#[derive(Debug)]\npub enum Error {\n");
+    if underflow {
+        out.push_str("    TapeUnderflow { pc: usize },\n");
+    }
+    if eof {
+        out.push_str("    UnexpectedEof { pc: usize },\n");
+    }
+    out.push_str("    Io { pc: usize, kind: io::ErrorKind },\n}\n\n");
+    out.push_str("impl fmt::Display for Error {\n");
+    out.push_str("    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {\n");
+    out.push_str("        match self {\n");
+    if underflow {
+        out.push_str("            Error::TapeUnderflow { pc } => {\n");
+        out.push_str("                write!(f, \"tape underflow at instruction {pc}\")\n");
+        out.push_str("            }\n");
+    }
+    if eof {
+        out.push_str("            Error::UnexpectedEof { pc } => {\n");
+        out.push_str(
+            "                write!(f, \"unexpected end of input at instruction {pc}\")\n",
+        );
+        out.push_str("            }\n");
+    }
+    out.push_str("            Error::Io { pc, kind } => {\n");
+    out.push_str(
+        "                write!(f, \"failed to write output at instruction {pc}: {kind}\")\n",
+    );
+    out.push_str("            }\n        }\n    }\n}\n\n");
+    out.push_str("impl std::error::Error for Error {}\n");
+
+    out.push_str(RUST_TAPE);
+    if uses.cells {
+        out.push_str("\nimpl Tape {");
+        rust_at(&mut out, config.underflow);
+        out.push_str("}\n");
+    }
+    if uses.output {
+        out.push_str(RUST_PUT);
+        match config.output {
+            OutputMode::Bytes => {
+                out.push_str("    let result = output.write_all(&[value as u8]);\n")
+            }
+            OutputMode::Text => out.push_str(RUST_PUT_UTF8),
+        }
+        out.push_str("    result.map_err(|err| Error::Io {\n        pc,\n");
+        out.push_str("        kind: err.kind(),\n    })\n}\n");
+    }
+    if uses.input {
+        rust_get(&mut out, config.eof);
+    }
+
+    writeln!(
+        out,
+        "\n/// Run the program on a fresh tape, feeding `input` to ',' and writing what '.'\n\
+         /// produces to `output`\n\
+         pub fn run(input: &mut impl Read, output: &mut impl Write) -> Result<(), Error> {{\n    \
+         let result = execute(input, output);\n    \
+         let flushed = output.flush().map_err(|err| Error::Io {{\n        \
+         pc: {},\n        \
+         kind: err.kind(),\n    \
+         }});\n    \
+         result.and(flushed)\n\
+         }}",
+        program.instructions().len()
+    )
+    .unwrap();
+
+    let input = if uses.input { "input" } else { "_input" };
+    let output = if uses.input || uses.output {
+        "output"
+    } else {
+        "_output"
+    };
+    writeln!(
+        out,
+        "\nfn execute({input}: &mut impl Read, {output}: &mut impl Write) -> Result<(), Error> {{"
+    )
+    .unwrap();
+    let tape_len = match config.underflow {
+        UnderflowPolicy::Wrap => config.tape_len.max(1),
+        _ => 1,
+    };
+    let mutable = if uses.changes { "mut " } else { "" };
+    writeln!(
+        out,
+        "    let {mutable}t = Tape {{\n        cells: vec![0; {tape_len}],\n        p: 0,\n    }};"
+    )
+    .unwrap();
+
+    // where the cell `offset` away from the data pointer is
+    let at = |offset: isize, pc: usize| match config.underflow {
+        UnderflowPolicy::Error => format!("t.at({offset}, {pc})?"),
+        _ => format!("t.at({offset})"),
+    };
+    let prelude = program.prelude();
+    for (number, value) in prelude.tape.iter().enumerate() {
+        if *value != 0 {
+            writeln!(out, "    let i = {};", at(number as isize, 0)).unwrap();
+            writeln!(out, "    t.cells[i] = {value}u64 as Cell;").unwrap();
+        }
+    }
+    for value in &prelude.output {
+        writeln!(out, "    put(output, {value}, 0)?;").unwrap();
+    }
+    if prelude.memptr != 0 {
+        writeln!(out, "    t.p = {};", at(prelude.memptr as isize, 0)).unwrap();
+    }
+
+    let mut depth = 1;
+    for (pc, inst) in program.instructions().iter().enumerate() {
+        if let Token::Close(_) = inst.token {
+            depth -= 1;
+        }
+        let indent = "    ".repeat(depth);
+        let line = match inst.token {
+            Token::Move(amount) => format!("t.p = {};", at(amount, pc)),
+            Token::Add { offset, value } => format!(
+                "let i = {};\n{indent}t.cells[i] = t.cells[i].wrapping_add({value}u64 as Cell);",
+                at(offset, pc)
+            ),
+            Token::Set(value) => format!("t.cells[t.p] = {value}u64 as Cell;"),
+            Token::MulAdd { offset, factor } => format!(
+                "if t.cells[t.p] != 0 {{\n\
+                 {indent}    let i = {};\n\
+                 {indent}    let product = t.cells[t.p].wrapping_mul({factor}u64 as Cell);\n\
+                 {indent}    t.cells[i] = t.cells[i].wrapping_add(product);\n\
+                 {indent}}}",
+                at(offset, pc)
+            ),
+            Token::ScanRight(stride) => format!(
+                "while t.cells[t.p] != 0 {{\n{indent}    t.p = {};\n{indent}}}",
+                at(stride as isize, pc)
+            ),
+            Token::ScanLeft(stride) => format!(
+                "while t.cells[t.p] != 0 {{\n{indent}    t.p = {};\n{indent}}}",
+                at(-(stride as isize), pc)
+            ),
+            Token::Open(_) => "while t.cells[t.p] != 0 {".to_string(),
+            Token::Close(_) => "}".to_string(),
+            Token::Input => format!("get(&mut t, input, output, {pc})?;"),
+            Token::Output => format!("put(output, t.cells[t.p] as u64, {pc})?;"),
+        };
+        writeln!(out, "{indent}{line}").unwrap();
+        if let Token::Open(_) = inst.token {
+            depth += 1;
+        }
+    }
+    out.push_str("    Ok(())\n}\n");
+    out
+}
+
+/// Write the Rust method finding the cell some distance from the data
+/// pointer, growing the tape or failing as `underflow` says
+fn rust_at(out: &mut String, underflow: UnderflowPolicy) {
+    out.push_str("\n    /// Index of the cell `offset` cells away from the data pointer\n");
+    match underflow {
+        UnderflowPolicy::Wrap => out.push_str(
+            "    fn at(&self, offset: isize) -> usize {\n        \
+             let len = self.cells.len() as isize;\n        \
+             (self.p as isize + offset % len).rem_euclid(len) as usize\n    \
+             }\n",
+        ),
+        UnderflowPolicy::Error => out.push_str(
+            "    fn at(&mut self, offset: isize, pc: usize) -> Result<usize, Error> {\n        \
+             let i = self\n            \
+             .p\n            \
+             .checked_add_signed(offset)\n            \
+             .ok_or(Error::TapeUnderflow { pc })?;\n        \
+             self.grow_right(i);\n        \
+             Ok(i)\n    \
+             }\n",
+        ),
+        UnderflowPolicy::Grow => out.push_str(
+            "    fn at(&mut self, offset: isize) -> usize {\n        \
+             if let Some(i) = self.p.checked_add_signed(offset) {\n            \
+             self.grow_right(i);\n            \
+             return i;\n        \
+             }\n        \
+             let added = (offset.unsigned_abs() - self.p).max(self.cells.len());\n        \
+             self.cells.splice(0..0, std::iter::repeat(0).take(added));\n        \
+             self.p += added;\n        \
+             self.p - offset.unsigned_abs()\n    \
+             }\n",
+        ),
+    }
+    if underflow != UnderflowPolicy::Wrap {
+        out.push_str(RUST_GROW_RIGHT);
+    }
+}
+
+/// Write the Rust function reading a byte of input, handling its end as `eof` says
+fn rust_get(out: &mut String, eof: EofPolicy) {
+    out.push_str(RUST_GET);
+    let on_eof = match eof {
+        EofPolicy::Zero => "t.cells[t.p] = 0,",
+        EofPolicy::MinusOne => "t.cells[t.p] = Cell::MAX,",
+        EofPolicy::Unchanged => "{}",
+        EofPolicy::Error => "return Err(Error::UnexpectedEof { pc }),",
+    };
+    writeln!(out, "        Err(_) => {on_eof}\n    }}\n    Ok(())\n}}").unwrap();
+}
+
+/// Write the C function finding the cell some distance from the data
+/// pointer, growing the tape or failing as `underflow` says
+fn c_at(out: &mut String, underflow: UnderflowPolicy) {
+    out.push_str("\n/* index of the cell `offset` cells away from the data pointer */\n");
+    out.push_str("static size_t at(ptrdiff_t offset, size_t pc) {\n");
+    match underflow {
+        UnderflowPolicy::Wrap => {
+            out.push_str("    ptrdiff_t n = (ptrdiff_t)len;\n");
+            out.push_str("    (void)pc;\n");
+            out.push_str("    return (size_t)((((ptrdiff_t)p + offset % n) % n + n) % n);\n");
+        }
+        UnderflowPolicy::Error => {
+            out.push_str("    if (offset < 0 && (size_t)-offset > p) {\n");
+            out.push_str("        fail(\"tape underflow\", pc);\n    }\n");
+            out.push_str("    grow_right(p + offset);\n    return p + offset;\n");
+        }
+        UnderflowPolicy::Grow => {
+            out.push_str("    (void)pc;\n");
+            out.push_str("    if (offset < 0 && (size_t)-offset > p) {\n");
+            out.push_str("        grow_left((size_t)-offset - p);\n    }\n");
+            out.push_str("    grow_right(p + offset);\n    return p + offset;\n");
+        }
+    }
+    out.push_str("}\n");
+}
+
+/// Write the C function reading a byte of input, handling its end as `eof` says
+fn c_get(out: &mut String, eof: EofPolicy) {
+    out.push_str("\n/* read a byte into the current cell */\nstatic void get(size_t pc) {\n");
+    out.push_str("    fflush(stdout);\n    int c = getchar();\n");
+    out.push_str("    if (c != EOF) {\n        tape[p] = (cell)c;\n        return;\n    }\n");
+    match eof {
+        EofPolicy::Zero => out.push_str("    (void)pc;\n    tape[p] = 0;\n"),
+        EofPolicy::MinusOne => out.push_str("    (void)pc;\n    tape[p] = (cell)-1;\n"),
+        EofPolicy::Unchanged => out.push_str("    (void)pc;\n"),
+        EofPolicy::Error => out.push_str("    fail(\"unexpected end of input\", pc);\n"),
+    }
+    out.push_str("}\n");
+}
+
+/// Which parts of the runtime a program needs
+struct Uses {
+    /// Moving the data pointer, or changing cells other than the current one
+    cells: bool,
+    /// Writing to any cell at all
+    changes: bool,
+    input: bool,
+    output: bool,
+}
+
+impl Uses {
+    fn of(program: &Program) -> Self {
+        let prelude = program.prelude();
+        let mut uses = Uses {
+            cells: !prelude.tape.is_empty() || prelude.memptr != 0,
+            changes: !prelude.tape.is_empty() || prelude.memptr != 0,
+            input: false,
+            output: !prelude.output.is_empty(),
+        };
+        for inst in program.instructions() {
+            if !matches!(inst.token, Token::Open(_) | Token::Close(_) | Token::Output) {
+                uses.changes = true;
+            }
+            match inst.token {
+                Token::Input => uses.input = true,
+                Token::Output => uses.output = true,
+                Token::Set(_) | Token::Open(_) | Token::Close(_) => {}
+                _ => uses.cells = true,
+            }
+        }
+        uses
+    }
+}
+
+/// Comma separated values for an array initializer
+fn join(values: &[u64]) -> String {
+    let values: Vec<String> = values.iter().map(|value| format!("{value}u")).collect();
+    values.join(", ")
+}
+
+/// The tape of a C program
+const C_TAPE: &str = r#"static cell *tape;
+/* number of cells on the tape */
+static size_t len;
+/* data pointer, as an index into the tape */
+static size_t p;
+"#;
+
+/// Error reporting for C programs
+const C_FAIL: &str = r#"
+static void fail(const char *what, size_t pc) {
+    fflush(stdout);
+    fprintf(stderr, "error: %s at instruction %zu\n", what, pc);
+    exit(1);
+}
+"#;
+
+/// Growing the tape to the right, for C programs whose tape doesn't wrap
+const C_GROW_RIGHT: &str = r#"
+/* make sure the cell at index `i` exists */
+static void grow_right(size_t i) {
+    if (i < len) {
+        return;
+    }
+    size_t new_len = len * 2 > i + 1 ? len * 2 : i + 1;
+    tape = realloc(tape, new_len * sizeof(cell));
+    if (!tape) {
+        abort();
+    }
+    memset(tape + len, 0, (new_len - len) * sizeof(cell));
+    len = new_len;
+}
+"#;
+
+/// Growing the tape to the left, for C programs with [`UnderflowPolicy::Grow`]
+const C_GROW_LEFT: &str = r#"
+/* add at least `missing` cells to the left */
+static void grow_left(size_t missing) {
+    size_t added = missing > len ? missing : len;
+    cell *cells = calloc(len + added, sizeof(cell));
+    if (!cells) {
+        abort();
+    }
+    memcpy(cells + added, tape, len * sizeof(cell));
+    free(tape);
+    tape = cells;
+    len += added;
+    p += added;
+}
+"#;
+
+/// Body of `put` writing a cell as a UTF-8 encoded character
+const C_PUT_UTF8: &str = r#"    unsigned char bytes[4];
+    size_t n;
+    if (v > 0x10FFFF || (v >= 0xD800 && v <= 0xDFFF)) {
+        v = 0xFFFD;
+    }
+    if (v < 0x80) {
+        bytes[0] = (unsigned char)v;
+        n = 1;
+    } else if (v < 0x800) {
+        bytes[0] = (unsigned char)(0xC0 | v >> 6);
+        bytes[1] = (unsigned char)(0x80 | (v & 0x3F));
+        n = 2;
+    } else if (v < 0x10000) {
+        bytes[0] = (unsigned char)(0xE0 | v >> 12);
+        bytes[1] = (unsigned char)(0x80 | (v >> 6 & 0x3F));
+        bytes[2] = (unsigned char)(0x80 | (v & 0x3F));
+        n = 3;
+    } else {
+        bytes[0] = (unsigned char)(0xF0 | v >> 18);
+        bytes[1] = (unsigned char)(0x80 | (v >> 12 & 0x3F));
+        bytes[2] = (unsigned char)(0x80 | (v >> 6 & 0x3F));
+        bytes[3] = (unsigned char)(0x80 | (v & 0x3F));
+        n = 4;
+    }
+    fwrite(bytes, 1, n, stdout);
+"#;
+
+/// The tape of a Rust program
+const RUST_TAPE: &str = r#"
+struct Tape {
+    cells: Vec<Cell>,
+    /// Data pointer, as an index into `cells`
+    p: usize,
+}
+"#;
+
+/// Growing the tape to the right, for Rust programs whose tape doesn't wrap
+const RUST_GROW_RIGHT: &str = r#"
+    /// Make sure the cell at index `i` exists
+    fn grow_right(&mut self, i: usize) {
+        if i >= self.cells.len() {
+            let len = (self.cells.len() * 2).max(i + 1);
+            self.cells.resize(len, 0);
+        }
+    }
+"#;
+
+/// Start of `put`, up to writing the cell
+const RUST_PUT: &str = r#"
+/// Write a cell out
+fn put(output: &mut impl Write, value: u64, pc: usize) -> Result<(), Error> {
+"#;
+
+/// Writing a cell as a UTF-8 encoded character in `put`
+const RUST_PUT_UTF8: &str = r#"    let c = u32::try_from(value)
+        .ok()
+        .and_then(char::from_u32)
+        .unwrap_or(char::REPLACEMENT_CHARACTER);
+    let result = output.write_all(c.encode_utf8(&mut [0; 4]).as_bytes());
+"#;
+
+/// Start of `get`, up to handling the end of the input
+const RUST_GET: &str = r#"
+/// Read a byte into the current cell
+fn get(
+    t: &mut Tape,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    pc: usize,
+) -> Result<(), Error> {
+    output.flush().map_err(|err| Error::Io {
+        pc,
+        kind: err.kind(),
+    })?;
+    let mut byte = [0];
+    match input.read_exact(&mut byte) {
+        Ok(()) => t.cells[t.p] = byte[0].into(),
+"#;
+
+/// Entry point of a standalone Rust program
+const RUST_MAIN: &str = r#"
+fn main() -> std::process::ExitCode {
+    let mut output = io::BufWriter::new(io::stdout().lock());
+    match run(&mut io::stdin().lock(), &mut output) {
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {err}");
+            std::process::ExitCode::FAILURE
+        }
+    }
+}
+"#;
@@ -0,0 +1,177 @@
+use std::fmt;
+use std::io;
+
+use crate::token::Span;
+
+/// What went wrong while parsing a program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// A '[' that is never closed
+    UnmatchedOpen,
+    /// A ']' without a '[' before it
+    UnmatchedClose,
+}
+
+/// An error found while parsing a program, along with where in the source it happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    /// Byte offset of the offending character in the source
+    pub offset: usize,
+    /// Line of the offending character, starting at 1
+    pub line: usize,
+    /// Column of the offending character in characters, starting at 1
+    pub column: usize,
+}
+
+impl ParseError {
+    /// Create an error for the character at `offset` in `source`, working out its line and column
+    pub fn new(kind: ParseErrorKind, source: &[u8], offset: usize) -> Self {
+        let (line, column) = line_column(source, offset);
+        ParseError {
+            kind,
+            offset,
+            line,
+            column,
+        }
+    }
+
+    /// Render the offending line of `source` with a caret under the error, e.g.
+    ///
+    /// ```text
+    ///   |
+    /// 3 | +++[>+
+    ///   |    ^ unmatched '['
+    /// ```
+    pub fn snippet(&self, source: &[u8]) -> String {
+        let span = Span::new(self.offset, self.offset + 1);
+        snippet(source, span, &self.kind.to_string())
+    }
+}
+
+/// An error that stopped a program while it was running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeError {
+    /// The data pointer was moved left of the first cell
+    TapeUnderflow {
+        /// Position of the offending instruction in the program
+        pc: usize,
+        /// Where the offending instruction came from in the source
+        span: Span,
+    },
+    /// ',' was executed after the input ran out, with [`EofPolicy::Error`](crate::EofPolicy::Error)
+    UnexpectedEof {
+        /// Position of the offending instruction in the program
+        pc: usize,
+        /// Where the offending instruction came from in the source
+        span: Span,
+    },
+    /// Writing the program's output failed
+    Io {
+        /// Position of the instruction whose output couldn't be written
+        pc: usize,
+        /// Where the offending instruction came from in the source
+        span: Span,
+        kind: io::ErrorKind,
+    },
+}
+
+impl RuntimeError {
+    /// Where in the source the error happened
+    pub fn span(&self) -> Span {
+        match self {
+            RuntimeError::TapeUnderflow { span, .. } => *span,
+            RuntimeError::UnexpectedEof { span, .. } => *span,
+            RuntimeError::Io { span, .. } => *span,
+        }
+    }
+
+    /// Render the offending line of `source` with the instruction that failed underlined
+    pub fn snippet(&self, source: &[u8]) -> String {
+        let label = match self {
+            RuntimeError::TapeUnderflow { .. } => "moved left of the first cell",
+            RuntimeError::UnexpectedEof { .. } => "read past the end of the input",
+            RuntimeError::Io { .. } => "failed to write output",
+        };
+        snippet(source, self.span(), label)
+    }
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseErrorKind::UnmatchedOpen => write!(f, "unmatched '['"),
+            ParseErrorKind::UnmatchedClose => write!(f, "unmatched ']'"),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at line {}, column {}",
+            self.kind, self.line, self.column
+        )
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeError::TapeUnderflow { pc, .. } => {
+                write!(f, "tape underflow at instruction {pc}")
+            }
+            RuntimeError::UnexpectedEof { pc, .. } => {
+                write!(f, "unexpected end of input at instruction {pc}")
+            }
+            RuntimeError::Io { pc, kind, .. } => {
+                write!(f, "failed to write output at instruction {pc}: {kind}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// Line and column (both starting at 1) of the character at `offset` in `source`
+fn line_column(source: &[u8], offset: usize) -> (usize, usize) {
+    let line = source[..offset].iter().filter(|&&b| b == b'\n').count() + 1;
+    let column = String::from_utf8_lossy(&source[line_start(source, offset)..offset])
+        .chars()
+        .count()
+        + 1;
+    (line, column)
+}
+
+/// Render the line of `source` containing the start of `span`, with the span
+/// underlined by carets and followed by `label`
+fn snippet(source: &[u8], span: Span, label: &str) -> String {
+    let start = line_start(source, span.start);
+    let end = source[span.start..]
+        .iter()
+        .position(|&b| b == b'\n')
+        .map_or(source.len(), |pos| span.start + pos);
+    let text = String::from_utf8_lossy(&source[start..end]);
+    let text = text.trim_end_matches('\r');
+    let (line, column) = line_column(source, span.start);
+    let number = line.to_string();
+    let gutter = " ".repeat(number.len());
+    let pad = " ".repeat(column - 1);
+    let width = String::from_utf8_lossy(&source[span.start..span.end.min(end)])
+        .chars()
+        .count()
+        .max(1);
+    let carets = "^".repeat(width);
+    format!("{gutter} |\n{number} | {text}\n{gutter} | {pad}{carets} {label}\n")
+}
+
+/// Byte offset of the start of the line containing `offset`
+fn line_start(source: &[u8], offset: usize) -> usize {
+    source[..offset]
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |pos| pos + 1)
+}
@@ -0,0 +1,419 @@
+//! A JIT compiler turning a [`Program`] into x86-64 machine code, for Linux.
+//!
+//! The whole program is compiled up front into a single function, which keeps
+//! the tape's address, the data pointer and the tape's length in registers.
+//! Everything else goes through the callbacks in [`runtime`](crate::runtime).
+
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+
+use crate::cell::Cell;
+use crate::error::RuntimeError;
+use crate::machine::{Config, UnderflowPolicy};
+use crate::program::{Prelude, Program};
+use crate::runtime::{self, CELLS, LEN, MEMPTR};
+use crate::token::{Span, Token};
+
+/// A program compiled to machine code for cells of type `C`, ready to be run
+/// any number of times
+///
+/// Running it behaves exactly like running the program on a fresh
+/// [`Machine`](crate::Machine) with the same [`Config`]:
+///
+/// ```
+/// use stupidfuck::{Config, Jit, Program};
+///
+/// let mut program = Program::parse(b",[.,]")?;
+/// program.optimize();
+/// let jit = Jit::<u8>::compile(&program, Config::default())?;
+/// let mut output = Vec::new();
+/// jit.run(&b"echo"[..], &mut output)?;
+/// assert_eq!(output, b"echo");
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug)]
+pub struct Jit<C: Cell = u8> {
+    code: Code,
+    /// Span of each instruction, for errors
+    spans: Vec<Span>,
+    prelude: Prelude,
+    config: Config,
+    cell: PhantomData<C>,
+}
+
+impl<C: Cell> Jit<C> {
+    /// Compile `program` for a machine with the given [`Config`]
+    ///
+    /// Fails if the program needs more than 2GiB of machine code, or the
+    /// memory for it can't be mapped.
+    pub fn compile(program: &Program, config: Config) -> io::Result<Self> {
+        const { assert!(size_of::<C>() * 8 == C::BITS as usize) };
+        let code = Code::new(&compile::<C>(program, config)?)?;
+        Ok(Jit {
+            code,
+            spans: program
+                .instructions()
+                .iter()
+                .map(|inst| inst.span)
+                .collect(),
+            prelude: program.prelude().clone(),
+            config,
+            cell: PhantomData,
+        })
+    }
+
+    /// Run the program on a fresh tape, feeding `input` to ',' and writing
+    /// whatever '.' produces to `output`, flushing it like
+    /// [`Machine::run`](crate::Machine::run)
+    pub fn run<R: Read, W: Write>(&self, input: R, output: W) -> Result<(), RuntimeError> {
+        // SAFETY: the code was compiled for cells of type `C`
+        unsafe {
+            runtime::run::<C, R, W>(
+                self.code.entry(),
+                &self.spans,
+                &self.prelude,
+                self.config,
+                input,
+                output,
+            )
+        }
+    }
+}
+
+/// Machine code for `program`, as a function taking a `*mut State<C>` and
+/// returning nonzero if the program failed, or an error if the code is too
+/// long for its jumps to reach across
+///
+/// Registers used throughout:
+/// - `rbx`: address of the tape's first cell
+/// - `r12`: data pointer
+/// - `r13`: number of cells on the tape
+/// - `r14`: the `State`
+/// - `rax`: index of the cell being worked on
+/// - `rcx`, `rdx`: scratch
+fn compile<C: Cell>(program: &Program, config: Config) -> io::Result<Vec<u8>> {
+    let mut asm = Asm {
+        code: Vec::new(),
+        scale: (C::BITS / 8).trailing_zeros() as u8,
+        config,
+        reach: runtime::reach::<C> as *const () as usize,
+        exits: Vec::new(),
+    };
+    // prologue, keeping the stack 16 byte aligned for calls
+    asm.emit(&[0x53]); // push rbx
+    asm.emit(&[0x41, 0x54]); // push r12
+    asm.emit(&[0x41, 0x55]); // push r13
+    asm.emit(&[0x41, 0x56]); // push r14
+    asm.emit(&[0x48, 0x83, 0xEC, 0x08]); // sub rsp, 8
+    asm.emit(&[0x49, 0x89, 0xFE]); // mov r14, rdi
+    asm.reload();
+
+    let mut opens = Vec::new();
+    for (pc, inst) in program.instructions().iter().enumerate() {
+        match inst.token {
+            Token::Move(amount) => {
+                asm.index(amount, pc);
+                asm.emit(&[0x49, 0x89, 0xC4]); // mov r12, rax
+            }
+            Token::Add { offset, value } => {
+                asm.index(offset, pc);
+                asm.mov_imm(RCX, value);
+                asm.cell(Op::Add, RAX);
+            }
+            Token::Set(value) => {
+                asm.mov_imm(RCX, value);
+                asm.cell(Op::Store, R12);
+            }
+            Token::MulAdd { offset, factor } => {
+                asm.cell(Op::Load, R12);
+                asm.emit(&[0x48, 0x85, 0xC9]); // test rcx, rcx
+                let skip = asm.jump(JZ);
+                asm.index(offset, pc);
+                // reaching the cell may have moved the tape
+                asm.cell(Op::Load, R12);
+                asm.mov_imm(RDX, factor);
+                asm.emit(&[0x48, 0x0F, 0xAF, 0xCA]); // imul rcx, rdx
+                asm.cell(Op::Add, RAX);
+                asm.land(skip);
+            }
+            Token::ScanRight(stride) => asm.scan(stride as isize, pc),
+            Token::ScanLeft(stride) => asm.scan(-(stride as isize), pc),
+            Token::Open(_) => {
+                asm.cell(Op::Load, R12);
+                asm.emit(&[0x48, 0x85, 0xC9]); // test rcx, rcx
+                let exit = asm.jump(JZ);
+                opens.push((exit, asm.code.len()));
+            }
+            Token::Close(_) => {
+                let (exit, body) = opens.pop().expect("brackets are matched");
+                asm.cell(Op::Load, R12);
+                asm.emit(&[0x48, 0x85, 0xC9]); // test rcx, rcx
+                let back = asm.jump(JNZ);
+                asm.patch(back, body);
+                asm.land(exit);
+            }
+            Token::Input => {
+                asm.mov_imm(RSI, pc as u64);
+                asm.call(runtime::input::<C> as *const () as usize);
+            }
+            Token::Output => {
+                asm.cell(Op::Load, R12);
+                asm.emit(&[0x48, 0x89, 0xCE]); // mov rsi, rcx
+                asm.mov_imm(RDX, pc as u64);
+                asm.call(runtime::output::<C> as *const () as usize);
+            }
+        }
+    }
+
+    // epilogue, returning 0 normally or whatever the failing callback returned
+    asm.emit(&[0x31, 0xC0]); // xor eax, eax
+    for exit in std::mem::take(&mut asm.exits) {
+        asm.land(exit);
+    }
+    asm.emit(&[0x48, 0x83, 0xC4, 0x08]); // add rsp, 8
+    asm.emit(&[0x41, 0x5E]); // pop r14
+    asm.emit(&[0x41, 0x5D]); // pop r13
+    asm.emit(&[0x41, 0x5C]); // pop r12
+    asm.emit(&[0x5B]); // pop rbx
+    asm.emit(&[0xC3]); // ret
+                       // jumps stay inside the code, so their offsets fit if its length does
+    if i32::try_from(asm.code.len()).is_err() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "program needs more than 2GiB of machine code",
+        ));
+    }
+    Ok(asm.code)
+}
+
+/// Register numbers, as used in instruction encodings
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+const RDX: u8 = 2;
+const RSI: u8 = 6;
+const R12: u8 = 12;
+
+/// Condition codes of conditional jumps
+const JB: u8 = 0x82;
+const JZ: u8 = 0x84;
+const JNZ: u8 = 0x85;
+
+/// What to do with the cell at `[rbx + index * cell size]`
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    /// Zero extend it into `rcx`
+    Load,
+    /// Overwrite it with `rcx`
+    Store,
+    /// Add `rcx` to it
+    Add,
+}
+
+/// Buffer for the machine code of one program
+struct Asm {
+    code: Vec<u8>,
+    /// Log2 of the size of a cell in bytes
+    scale: u8,
+    config: Config,
+    /// Address of [`runtime::reach`] for the program's cells
+    reach: usize,
+    /// Jumps to patch to the epilogue once it's known where it is
+    exits: Vec<usize>,
+}
+
+impl Asm {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    /// `mov reg, imm64`
+    fn mov_imm(&mut self, reg: u8, value: u64) {
+        self.emit(&[0x48, 0xB8 + reg]);
+        self.emit(&value.to_le_bytes());
+    }
+
+    /// Do `op` on the cell at index `rax` or `r12` with `rcx`, for the size of
+    /// a cell
+    fn cell(&mut self, op: Op, index: u8) {
+        let (prefix, opcode): (&[u8], &[u8]) = match (op, self.scale) {
+            (Op::Load, 0) => (&[], &[0x0F, 0xB6]),
+            (Op::Load, 1) => (&[], &[0x0F, 0xB7]),
+            (Op::Load, _) => (&[], &[0x8B]),
+            (Op::Store, 0) => (&[], &[0x88]),
+            (Op::Store, 1) => (&[0x66], &[0x89]),
+            (Op::Store, _) => (&[], &[0x89]),
+            (Op::Add, 0) => (&[], &[0x00]),
+            (Op::Add, 1) => (&[0x66], &[0x01]),
+            (Op::Add, _) => (&[], &[0x01]),
+        };
+        self.emit(prefix);
+        let rex = 0x40 | u8::from(self.scale == 3) << 3 | u8::from(index == R12) << 1;
+        if rex != 0x40 {
+            self.emit(&[rex]);
+        }
+        self.emit(opcode);
+        // [rbx + index * 2^scale], with rcx as the other operand
+        self.emit(&[
+            (RCX << 3) | 0b100,
+            self.scale << 6 | (index & 7) << 3 | 0b011,
+        ]);
+    }
+
+    /// Put the index of the cell `offset` cells from the data pointer in `rax`,
+    /// leaving through the epilogue if the program has to stop
+    fn index(&mut self, offset: isize, pc: usize) {
+        self.emit(&[0x4C, 0x89, 0xE0]); // mov rax, r12
+        if offset == 0 {
+            return;
+        }
+        if self.config.underflow == UnderflowPolicy::Wrap {
+            let len = self.config.tape_len.max(1);
+            self.add_rax(offset.rem_euclid(len as isize));
+            self.emit(&[0x4C, 0x39, 0xE8]); // cmp rax, r13
+            self.emit(&[0x72, 0x03]); // jb over the sub
+            self.emit(&[0x4C, 0x29, 0xE8]); // sub rax, r13
+            return;
+        }
+        self.add_rax(offset);
+        // negative indices are past the end as unsigned numbers too
+        self.emit(&[0x4C, 0x39, 0xE8]); // cmp rax, r13
+        let on_tape = self.jump(JB);
+        self.mov_imm(RSI, offset as u64);
+        self.mov_imm(RDX, pc as u64);
+        self.call(self.reach);
+        self.emit(&[0x4C, 0x89, 0xE0]); // mov rax, r12
+        self.add_rax(offset);
+        self.land(on_tape);
+    }
+
+    /// `add rax, offset`
+    fn add_rax(&mut self, offset: isize) {
+        match i32::try_from(offset) {
+            Ok(offset) => {
+                self.emit(&[0x48, 0x05]);
+                self.emit(&offset.to_le_bytes());
+            }
+            Err(_) => {
+                self.mov_imm(RDX, offset as u64);
+                self.emit(&[0x48, 0x01, 0xD0]); // add rax, rdx
+            }
+        }
+    }
+
+    /// Move the data pointer `stride` cells at a time until it's on a zero
+    fn scan(&mut self, stride: isize, pc: usize) {
+        let top = self.code.len();
+        self.cell(Op::Load, R12);
+        self.emit(&[0x48, 0x85, 0xC9]); // test rcx, rcx
+        let done = self.jump(JZ);
+        self.index(stride, pc);
+        self.emit(&[0x49, 0x89, 0xC4]); // mov r12, rax
+        self.emit(&[0xE9]); // jmp top
+        let back = self.code.len();
+        self.emit(&[0; 4]);
+        self.patch(back, top);
+        self.land(done);
+    }
+
+    /// Call the callback at `address` with the state and whatever is in `rsi`
+    /// and `rdx`, then pick up the tape again and leave if it failed
+    fn call(&mut self, address: usize) {
+        self.emit(&[0x4D, 0x89, 0x66, MEMPTR]); // mov [r14 + MEMPTR], r12
+        self.emit(&[0x4C, 0x89, 0xF7]); // mov rdi, r14
+        self.mov_imm(RAX, address as u64);
+        self.emit(&[0xFF, 0xD0]); // call rax
+        self.reload();
+        self.emit(&[0x48, 0x85, 0xC0]); // test rax, rax
+        let exit = self.jump(JNZ);
+        self.exits.push(exit);
+    }
+
+    /// Load the tape's address, the data pointer and the tape's length from
+    /// the state
+    fn reload(&mut self) {
+        self.emit(&[0x49, 0x8B, 0x5E, CELLS]); // mov rbx, [r14 + CELLS]
+        self.emit(&[0x4D, 0x8B, 0x66, MEMPTR]); // mov r12, [r14 + MEMPTR]
+        self.emit(&[0x4D, 0x8B, 0x6E, LEN]); // mov r13, [r14 + LEN]
+    }
+
+    /// Emit a conditional jump, returning where its target goes
+    fn jump(&mut self, condition: u8) -> usize {
+        self.emit(&[0x0F, condition]);
+        let at = self.code.len();
+        self.emit(&[0; 4]);
+        at
+    }
+
+    /// Point the jump whose target is at `at` to here
+    fn land(&mut self, at: usize) {
+        self.patch(at, self.code.len());
+    }
+
+    /// Point the jump whose target is at `at` to `target`
+    ///
+    /// The offset is truncated to 32 bits; [`compile`] refuses code too long
+    /// for that to be exact.
+    fn patch(&mut self, at: usize, target: usize) {
+        let rel = target as i64 - (at as i64 + 4);
+        self.code[at..at + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+    }
+}
+
+/// Executable memory holding machine code
+#[derive(Debug)]
+struct Code {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Code {
+    /// Map `bytes` into memory and make them executable (but no longer writable)
+    fn new(bytes: &[u8]) -> io::Result<Self> {
+        let len = bytes.len();
+        // SAFETY: mapping fresh anonymous memory doesn't touch any existing memory
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let code = Code { ptr, len };
+        // SAFETY: the mapping is `len` bytes long and writable
+        unsafe {
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), ptr.cast::<u8>(), len);
+        }
+        // SAFETY: the mapping is ours, and nothing else refers to it yet
+        if unsafe { libc::mprotect(ptr, len, libc::PROT_READ | libc::PROT_EXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(code)
+    }
+
+    /// The code as a function
+    ///
+    /// # Safety
+    ///
+    /// The code has to be a function taking one pointer and returning a `u64`
+    /// following the C calling convention, and whatever it does with the
+    /// pointer has to be valid for the pointer it's called with.
+    unsafe fn entry(&self) -> runtime::Entry {
+        // SAFETY: the caller promises the code is such a function
+        unsafe { std::mem::transmute::<*mut libc::c_void, runtime::Entry>(self.ptr) }
+    }
+}
+
+impl Drop for Code {
+    fn drop(&mut self) {
+        // SAFETY: the mapping is ours, and the code can't be running
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
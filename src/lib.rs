@@ -0,0 +1,50 @@
+//! A brainfuck interpreter.
+//!
+//! Source code is turned into a [`Program`] with [`Program::parse`], optionally
+//! optimized with [`Program::optimize`] or an [`Optimizer`], and then executed on a [`Machine`]:
+//!
+//! ```
+//! use stupidfuck::{Machine, Program};
+//!
+//! let mut program = Program::parse(b"++++++++[>++++++++<-]>+.")?;
+//! program.optimize();
+//! let mut output = Vec::new();
+//! Machine::<u8>::new().run(&program, std::io::empty(), &mut output)?;
+//! assert_eq!(output, b"A");
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! The machine's type parameter picks the width of its cells; any type
+//! implementing [`Cell`] (`u8`, `u16`, `u32` or `u64`) can be used.
+//!
+//! Programs can also be translated to other languages with the backends in
+//! [`emit`], or, on x86-64 Linux, compiled to machine code and run with a
+//! `Jit`.
+
+mod cell;
+#[cfg(feature = "cranelift")]
+pub mod cranelift;
+pub mod emit;
+mod error;
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod jit;
+mod machine;
+pub mod optimize;
+mod program;
+#[cfg(any(
+    feature = "cranelift",
+    all(target_arch = "x86_64", target_os = "linux")
+))]
+mod runtime;
+mod tape;
+mod token;
+
+pub use cell::{Cell, CellWidth};
+pub use error::{ParseError, ParseErrorKind, RuntimeError};
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub use jit::Jit;
+pub use machine::{Config, EofPolicy, Machine, OutputMode, UnderflowPolicy};
+pub use optimize::{Optimizer, Pass};
+pub use program::{Prelude, Program};
+pub use tape::Tape;
+pub use token::{Instruction, Span, Token};
@@ -0,0 +1,440 @@
+use std::io::{Read, Write};
+
+use crate::cell::Cell;
+use crate::error::RuntimeError;
+use crate::program::{Prelude, Program};
+use crate::tape::Tape;
+use crate::token::{Span, Token};
+
+/// What to do when a program moves the data pointer left of the first cell
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnderflowPolicy {
+    /// Stop the program with [`RuntimeError::TapeUnderflow`]
+    #[default]
+    Error,
+    /// Use a fixed-size tape of [`Config::tape_len`] cells, where moving off
+    /// either end comes back in at the other
+    Wrap,
+    /// Extend the tape infinitely in both directions, adding zeroed cells to
+    /// the left as needed. Cells left of the origin get negative numbers.
+    Grow,
+}
+
+/// What ',' does once the input has run out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EofPolicy {
+    /// Set the current cell to 0
+    #[default]
+    Zero,
+    /// Set the current cell to -1, i.e. every bit set (255 for 8-bit cells)
+    MinusOne,
+    /// Leave the current cell as it is
+    Unchanged,
+    /// Stop the program with [`RuntimeError::UnexpectedEof`]
+    Error,
+}
+
+/// How '.' turns a cell into output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputMode {
+    /// Write the low byte of the cell as-is
+    #[default]
+    Bytes,
+    /// Write the Unicode character whose code point is the cell's value, encoded
+    /// as UTF-8, so 8-bit cells are read as Latin-1. Values that aren't valid
+    /// code points come out as U+FFFD.
+    Text,
+}
+
+/// Settings for a [`Machine`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    pub underflow: UnderflowPolicy,
+    pub eof: EofPolicy,
+    pub output: OutputMode,
+    /// Number of cells on the tape when [`UnderflowPolicy::Wrap`] is used,
+    /// otherwise the tape grows as needed
+    pub tape_len: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            underflow: UnderflowPolicy::default(),
+            eof: EofPolicy::default(),
+            output: OutputMode::default(),
+            tape_len: 30000,
+        }
+    }
+}
+
+/// Encapsulates everything required to run a brainfuck program, including its:
+/// - RAM
+/// - Pointer to memory
+/// - Pointer to code (program counter)
+///
+/// The code itself lives in a [`Program`], so one machine can run several programs
+/// one after another, each seeing the memory left behind by the last. Programs
+/// optimized for a fresh machine, see [`Optimizer::fresh_machine`](crate::Optimizer::fresh_machine), have to be
+/// run on one.
+///
+/// Each cell of RAM is a `C`, so the width of the cells (and where their
+/// arithmetic wraps around) is picked with e.g. `Machine::<u16>::new()`.
+#[derive(Debug, Clone)]
+pub struct Machine<C: Cell = u8> {
+    /// Pointer to memory/RAM (data pointer), as an index into `memory`
+    memptr: usize,
+    /// Pointer to code (program counter)
+    instptr: usize,
+    /// All of RAM
+    memory: Tape<C>,
+    config: Config,
+}
+
+impl<C: Cell> Default for Machine<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C: Cell> Machine<C> {
+    /// Create a machine with the default [`Config`]
+    pub fn new() -> Self {
+        Self::with_config(Config::default())
+    }
+
+    /// Create a machine with a single zeroed cell of RAM, or a full tape of them
+    /// if it wraps around
+    pub fn with_config(config: Config) -> Self {
+        let memory = match config.underflow {
+            UnderflowPolicy::Wrap => Tape::new(config.tape_len.max(1)),
+            _ => Tape::new(1),
+        };
+        Machine {
+            memptr: 0,
+            instptr: 0,
+            memory,
+            config,
+        }
+    }
+
+    /// All of RAM touched so far
+    pub fn memory(&self) -> &Tape<C> {
+        &self.memory
+    }
+
+    /// Number of the cell the data pointer is on, counting from the origin
+    pub fn memptr(&self) -> isize {
+        self.memptr as isize - self.memory.origin() as isize
+    }
+
+    /// Position of the instruction being (or last) executed
+    pub fn instptr(&self) -> usize {
+        self.instptr
+    }
+
+    /// Execute `program` from its first instruction, feeding `input` to ',' and
+    /// writing whatever '.' produces to `output`
+    ///
+    /// `output` is flushed whenever the program asks for input and once it stops,
+    /// so it can be buffered without prompts getting stuck. Both ends can be
+    /// anything implementing [`Read`]/[`Write`], e.g. a `&[u8]` and a `Vec<u8>`:
+    ///
+    /// ```
+    /// use stupidfuck::{Machine, Program};
+    ///
+    /// let program = Program::parse(b",[.,]")?;
+    /// let mut output = Vec::new();
+    /// Machine::<u8>::new().run(&program, &b"echo"[..], &mut output)?;
+    /// assert_eq!(output, b"echo");
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn run<R: Read, W: Write>(
+        &mut self,
+        program: &Program,
+        mut input: R,
+        mut output: W,
+    ) -> Result<(), RuntimeError> {
+        let result = self.execute(program, &mut input, &mut output);
+        let end = program
+            .instructions()
+            .last()
+            .map_or(0, |inst| inst.span.end);
+        let flushed = output.flush().map_err(|err| RuntimeError::Io {
+            pc: self.instptr,
+            span: Span::new(end, end),
+            kind: err.kind(),
+        });
+        result.and(flushed)
+    }
+
+    fn execute<R: Read, W: Write>(
+        &mut self,
+        program: &Program,
+        input: &mut R,
+        output: &mut W,
+    ) -> Result<(), RuntimeError> {
+        let inst = program.instructions();
+        self.instptr = 0;
+        run_prelude(self, program.prelude(), output)?;
+        while self.instptr < inst.len() {
+            match inst[self.instptr].token {
+                Token::Move(a) => move_data(self, a, inst[self.instptr].span)?,
+                Token::Add { offset, value } => {
+                    addbyte(self, offset, value, inst[self.instptr].span)?
+                }
+                Token::Set(a) => setbyte(self, a),
+                Token::ScanRight(a) => scan_right(self, a),
+                Token::ScanLeft(a) => scan_left(self, a, inst[self.instptr].span)?,
+                Token::MulAdd { offset, factor } => {
+                    muladd(self, offset, factor, inst[self.instptr].span)?
+                }
+                Token::Output => outbyte(self, output, inst[self.instptr].span)?,
+                Token::Input => inbyte(self, input, output, inst[self.instptr].span)?,
+                Token::Open(a) => {
+                    if self.memory[self.memptr].is_zero() {
+                        jump_forward(self, a);
+                    }
+                }
+                Token::Close(a) => {
+                    if !self.memory[self.memptr].is_zero() {
+                        jump_rev(self, a);
+                        continue;
+                    }
+                }
+            }
+            self.instptr += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Set up the tape as the program's prelude left it and write out its output,
+/// relative to where the data pointer is
+fn run_prelude<C: Cell, W: Write>(
+    state: &mut Machine<C>,
+    prelude: &Prelude,
+    output: &mut W,
+) -> Result<(), RuntimeError> {
+    for (number, &value) in prelude.tape.iter().enumerate() {
+        let index = right_of(state, number);
+        state.memory[index] = C::from_u64(value);
+    }
+    for &value in &prelude.output {
+        write_cell(state.config.output, output, value).map_err(|err| RuntimeError::Io {
+            pc: state.instptr,
+            span: prelude.span,
+            kind: err.kind(),
+        })?;
+    }
+    state.memptr = right_of(state, prelude.memptr);
+    Ok(())
+}
+
+/// Move data pointer to the right i.e. '>', or to the left i.e. '<'
+fn move_data<C: Cell>(
+    state: &mut Machine<C>,
+    amount: isize,
+    span: Span,
+) -> Result<(), RuntimeError> {
+    state.memptr = offset_of(state, amount, span)?;
+    Ok(())
+}
+
+/// Index of the cell `amount` cells right of the data pointer, adding it to the tape if needed
+fn right_of<C: Cell>(state: &mut Machine<C>, amount: usize) -> usize {
+    if state.config.underflow == UnderflowPolicy::Wrap {
+        return (state.memptr + amount % state.memory.len()) % state.memory.len();
+    }
+    let index = state.memptr + amount;
+    state.memory.grow_right(index);
+    index
+}
+
+/// Index of the cell `offset` cells away from the data pointer, see [`right_of`] and [`left_of`]
+fn offset_of<C: Cell>(
+    state: &mut Machine<C>,
+    offset: isize,
+    span: Span,
+) -> Result<usize, RuntimeError> {
+    if offset < 0 {
+        left_of(state, offset.unsigned_abs(), span)
+    } else {
+        Ok(right_of(state, offset as usize))
+    }
+}
+
+/// Index of the cell `amount` cells left of the data pointer, handling moving
+/// off the start of the tape as the [`UnderflowPolicy`] says
+///
+/// Growing the tape to the left shifts every index, including the data pointer's.
+fn left_of<C: Cell>(
+    state: &mut Machine<C>,
+    amount: usize,
+    span: Span,
+) -> Result<usize, RuntimeError> {
+    if let Some(index) = state.memptr.checked_sub(amount) {
+        return Ok(index);
+    }
+    match state.config.underflow {
+        UnderflowPolicy::Error => Err(RuntimeError::TapeUnderflow {
+            pc: state.instptr,
+            span,
+        }),
+        UnderflowPolicy::Wrap => {
+            let len = state.memory.len();
+            Ok((state.memptr + len - amount % len) % len)
+        }
+        UnderflowPolicy::Grow => {
+            let added = state.memory.grow_left(amount - state.memptr);
+            state.memptr += added;
+            Ok(state.memptr - amount)
+        }
+    }
+}
+
+/// Move data pointer right `stride` cells at a time until it points at a zero i.e. '[>]'
+fn scan_right<C: Cell>(state: &mut Machine<C>, stride: usize) {
+    if state.config.underflow == UnderflowPolicy::Wrap {
+        while !state.memory[state.memptr].is_zero() {
+            state.memptr = right_of(state, stride);
+        }
+        return;
+    }
+    // everything past the end of the tape is zero, so if there's no zero on it
+    // the scan stops on the first step off the end
+    let steps = C::find_zero(&state.memory[state.memptr..], stride)
+        .unwrap_or_else(|| (state.memory.len() - state.memptr).div_ceil(stride));
+    state.memptr = right_of(state, steps * stride);
+}
+
+/// Move data pointer left `stride` cells at a time until it points at a zero i.e. '[<]'
+fn scan_left<C: Cell>(
+    state: &mut Machine<C>,
+    stride: usize,
+    span: Span,
+) -> Result<(), RuntimeError> {
+    if state.config.underflow == UnderflowPolicy::Wrap {
+        while !state.memory[state.memptr].is_zero() {
+            state.memptr = left_of(state, stride, span)?;
+        }
+        return Ok(());
+    }
+    // if there's no zero on the tape the scan steps off the start of it, which
+    // is either an error or lands on a fresh zero cell
+    let steps =
+        C::rfind_zero(&state.memory[..=state.memptr], stride).unwrap_or(state.memptr / stride + 1);
+    state.memptr = left_of(state, steps * stride, span)?;
+    Ok(())
+}
+
+/// Add to the value `offset` cells away from the memory address referenced by the data pointer i.e. '+', '-' or '>>+<<'
+fn addbyte<C: Cell>(
+    state: &mut Machine<C>,
+    offset: isize,
+    value: u64,
+    span: Span,
+) -> Result<(), RuntimeError> {
+    let target = offset_of(state, offset, span)?;
+    state.memory[target] = state.memory[target].wrapping_add(C::from_u64(value));
+    Ok(())
+}
+
+/// Overwrite value at memory address referenced by the data pointer i.e. '[-]'
+fn setbyte<C: Cell>(state: &mut Machine<C>, value: u64) {
+    state.memory[state.memptr] = C::from_u64(value);
+}
+
+/// Add the value at the memory address referenced by the data pointer, times
+/// `factor`, to the cell `offset` cells away i.e. one step of '[->++<]'
+fn muladd<C: Cell>(
+    state: &mut Machine<C>,
+    offset: isize,
+    factor: u64,
+    span: Span,
+) -> Result<(), RuntimeError> {
+    let val = state.memory[state.memptr];
+    // the loop this came from wouldn't have run, so don't touch the tape at all
+    if val.is_zero() {
+        return Ok(());
+    }
+    let target = offset_of(state, offset, span)?;
+    let product = C::from_u64(val.to_u64().wrapping_mul(factor));
+    state.memory[target] = state.memory[target].wrapping_add(product);
+    Ok(())
+}
+
+/// Print out the value at the memory address referenced by the data pointer as a byte (or character, see [`OutputMode`]) to the output i.e. '.'
+fn outbyte<C: Cell, W: Write>(
+    state: &mut Machine<C>,
+    output: &mut W,
+    span: Span,
+) -> Result<(), RuntimeError> {
+    let val = state.memory[state.memptr].to_u64();
+    write_cell(state.config.output, output, val).map_err(|err| RuntimeError::Io {
+        pc: state.instptr,
+        span,
+        kind: err.kind(),
+    })
+}
+
+/// Write a cell's value to `output` as [`OutputMode`] says
+pub(crate) fn write_cell<W: Write + ?Sized>(
+    mode: OutputMode,
+    output: &mut W,
+    val: u64,
+) -> std::io::Result<()> {
+    match mode {
+        OutputMode::Bytes => output.write_all(&[val as u8]),
+        OutputMode::Text => {
+            let c = u32::try_from(val)
+                .ok()
+                .and_then(char::from_u32)
+                .unwrap_or(char::REPLACEMENT_CHARACTER);
+            output.write_all(c.encode_utf8(&mut [0; 4]).as_bytes())
+        }
+    }
+}
+
+/// Read a single character from the program's input, and write that character's ASCII value to the memory address referenced by the data pointer i.e. ','
+/// What gets written once the input runs out depends on the machine's [`EofPolicy`].
+/// Pending output is flushed first, so prompts show up before the program waits.
+fn inbyte<C: Cell, R: Read, W: Write>(
+    state: &mut Machine<C>,
+    input: &mut R,
+    output: &mut W,
+    span: Span,
+) -> Result<(), RuntimeError> {
+    output.flush().map_err(|err| RuntimeError::Io {
+        pc: state.instptr,
+        span,
+        kind: err.kind(),
+    })?;
+    let mut byte = [0];
+    let val = input.read_exact(&mut byte).ok().map(|()| byte[0]);
+
+    state.memory[state.memptr] = match (val, state.config.eof) {
+        (Some(val), _) => C::from_u64(val.into()),
+        (None, EofPolicy::Zero) => C::default(),
+        (None, EofPolicy::MinusOne) => C::from_u64(u64::MAX),
+        (None, EofPolicy::Unchanged) => return Ok(()),
+        (None, EofPolicy::Error) => {
+            return Err(RuntimeError::UnexpectedEof {
+                pc: state.instptr,
+                span,
+            });
+        }
+    };
+    Ok(())
+}
+
+/// Execute the code inside the following set of square brackets (in code) if the value at the memory address referenced by the data pointer is 0 i.e. '['
+/// And keep doing it over and over again until value at the pointed-to memory address is 0.
+fn jump_forward<C: Cell>(state: &mut Machine<C>, pos: usize) {
+    state.instptr = pos;
+}
+
+/// Signify the end of a repeated code section i.e. ']'
+fn jump_rev<C: Cell>(state: &mut Machine<C>, pos: usize) {
+    state.instptr = pos;
+}
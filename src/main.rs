@@ -1,251 +1,402 @@
-/// Encapsulates everything required to run a brainfuck program, including its:
-/// - RAM
-/// - Pointer to memory
-/// - Code (instruction data)
-/// - Pointer to code (program counter)
-#[derive(Debug)]
-struct State {
-    /// Pointer to memory/RAM (data pointer)
-    memptr: usize,
-    /// Pointer to code (program counter)
-    instptr: usize,
-    /// All of RAM
-    memory: Vec<u8>,
-    /// All code (instruction data)
-    inst: Vec<Token>,
-    /// Pointer to the last character in the code
-    last: usize,
-}
-impl State {
-    fn new() -> Self {
-        State { memptr: 0, instptr: 0, memory: Vec::with_capacity(4096), inst: Vec::with_capacity(4096), last: 0 }
-    }
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, IsTerminal, Read, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::{Parser, ValueEnum};
+#[cfg(feature = "cranelift")]
+use stupidfuck::cranelift;
+use stupidfuck::{
+    emit, Cell, CellWidth, Config, EofPolicy, Machine, Optimizer, OutputMode, Pass, Program,
+    RuntimeError, UnderflowPolicy,
+};
+
+/// Run brainfuck programs from a file, the command line or stdin
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// Path to the program to run, or `-` to read it from stdin
+    #[arg(required_unless_present = "execute", conflicts_with = "execute")]
+    program: Option<PathBuf>,
+    /// Run the given code instead of reading it from a file
+    #[arg(short, long, value_name = "CODE")]
+    execute: Option<String>,
+    /// File to read the data fed to `,` from (defaults to stdin)
+    #[arg(short, long, value_name = "FILE")]
+    input: Option<PathBuf>,
+    /// What happens when the program moves left of the first cell
+    #[arg(long, value_enum, default_value_t = Underflow::Error)]
+    underflow: Underflow,
+    /// What `,` does once the input has run out
+    #[arg(long, value_enum, default_value_t = Eof::Zero)]
+    eof: Eof,
+    /// Write cells as UTF-8 encoded characters instead of raw bytes
+    /// (8-bit cells are then read as Latin-1)
+    #[arg(long)]
+    text: bool,
+    /// Number of cells on the tape with `--underflow wrap`
+    #[arg(long, value_name = "CELLS", default_value_t = Config::default().tape_len)]
+    tape_len: usize,
+    /// Width of each memory cell in bits
+    #[arg(long, value_enum, value_name = "BITS", default_value_t = CellBits::B8)]
+    cell_bits: CellBits,
+    /// Optimization level, from 0 (none) to 3 (every pass)
+    #[arg(
+        short = 'O',
+        value_name = "LEVEL",
+        default_value_t = 3,
+        value_parser = clap::value_parser!(u8).range(0..=3)
+    )]
+    opt_level: u8,
+    /// Comma separated passes to run on top of the optimization level, or to
+    /// skip when prefixed with `no-` (merge, cancel, clear, mul, scan, dead, offset, fold)
+    #[arg(long, value_name = "PASSES", value_delimiter = ',', value_parser = parse_pass)]
+    passes: Vec<(Pass, bool)>,
+    /// Print the program in another form instead of running it
+    #[arg(long, value_enum, value_name = "FORM")]
+    emit: Option<Emit>,
+    /// Compile the program to machine code and run that instead of
+    /// interpreting it (x86-64 Linux only)
+    #[arg(long, conflicts_with = "emit")]
+    jit: bool,
+    /// Compile the program with Cranelift and run that instead of
+    /// interpreting it
+    #[cfg(feature = "cranelift")]
+    #[arg(long, conflicts_with_all = ["emit", "jit"])]
+    cranelift: bool,
 }
 
-#[derive(Debug, Clone, Copy)]
-enum Token {
-    Right(usize),
-    Left(usize),
-    Incriment(u8),
-    Decriment(u8),
-    Open(usize),
-    Close(usize),
-    Input,
-    Output,
+/// Forms `--emit` can print a program in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Emit {
+    /// The instructions after parsing and after each optimization pass
+    Ir,
+    /// A C program
+    C,
+    /// A Rust program
+    Rust,
+    /// An object file compiled by Cranelift, to link into an executable
+    /// with e.g. `cc`
+    #[cfg(feature = "cranelift")]
+    Object,
 }
 
-/// Move data pointer to the right i.e. '>'
-fn inc_data(state: &mut State, amount: usize) {
-    state.memptr += amount;
-    if state.memptr >= state.memory.len() {
-        for _i in 0..=state.memptr-state.memory.len() {
-            state.memory.push(0);
+/// What runs a program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Backend {
+    Interpreter,
+    Jit,
+    #[cfg(feature = "cranelift")]
+    Cranelift,
+}
+
+impl Backend {
+    /// The backend the arguments ask for
+    fn of(args: &Args) -> Self {
+        #[cfg(feature = "cranelift")]
+        if args.cranelift {
+            return Backend::Cranelift;
+        }
+        if args.jit {
+            Backend::Jit
+        } else {
+            Backend::Interpreter
         }
     }
 }
 
-/// Move data pointer to the left i.e. '<'
-fn dec_data(state: &mut State, amount: usize) {
-    state.memptr -= amount;
+/// Parse a `--passes` entry into the pass and whether to run it
+fn parse_pass(entry: &str) -> Result<(Pass, bool), String> {
+    let (name, enabled) = match entry.strip_prefix("no-") {
+        Some(name) => (name, false),
+        None => (entry, true),
+    };
+    let pass = Pass::ALL
+        .into_iter()
+        .find(|pass| pass.name() == name)
+        .ok_or_else(|| format!("unknown pass {name:?}"))?;
+    Ok((pass, enabled))
 }
 
-/// Increment value at memory address referenced by the data pointer i.e. '+'
-fn incbyte(state: &mut State, amount: u8) {
-    state.memory[state.memptr] = state.memory[state.memptr].wrapping_add(amount);
+/// Command line names for [`UnderflowPolicy`]
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Underflow {
+    /// Stop with an error
+    Error,
+    /// Wrap around to the end of a fixed-size tape
+    Wrap,
+    /// Extend the tape infinitely in both directions
+    Grow,
 }
 
-/// Decrement value at memory address referenced by the data pointer i.e. '-'
-fn decbyte(state: &mut State, amount: u8) {
-    state.memory[state.memptr] = state.memory[state.memptr].wrapping_sub(amount);
+/// Command line names for [`EofPolicy`]
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Eof {
+    /// Set the cell to 0
+    Zero,
+    /// Set the cell to -1 (255 for 8-bit cells)
+    MinusOne,
+    /// Leave the cell unchanged
+    Unchanged,
+    /// Stop with an error
+    Error,
 }
 
-/// Print out the value at the memory address referenced by the data pointer as an ASCII character to stdout i.e. '.'
-fn outbyte(state: &mut State) {
-    print!("{}", state.memory[state.memptr] as char);
+impl From<Eof> for EofPolicy {
+    fn from(eof: Eof) -> Self {
+        match eof {
+            Eof::Zero => EofPolicy::Zero,
+            Eof::MinusOne => EofPolicy::MinusOne,
+            Eof::Unchanged => EofPolicy::Unchanged,
+            Eof::Error => EofPolicy::Error,
+        }
+    }
 }
 
-/// Prompt user for a single character via stdin, and once they do that, write that character's ASCII value to the memory address referenced by the data pointer i.e. ','
-fn inbyte(state: &mut State) {
-    let val = std::io::Read::bytes(std::io::stdin())
-        .next()
-        .and_then(|result| result.ok())
-        .unwrap_or(0);
-
-    state.memory[state.memptr] = val;
+/// Widths a memory cell can have
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum CellBits {
+    #[value(name = "8")]
+    B8,
+    #[value(name = "16")]
+    B16,
+    #[value(name = "32")]
+    B32,
+    #[value(name = "64")]
+    B64,
 }
 
-/// Find the position of the closing ]
-fn forward_ofset(state: &mut State, pos: usize) -> usize {
-    let mut local_level = 1;
-    let mut pos: usize = pos;
-    while local_level != 0 {
-        pos += 1;
-        match state.inst[pos] {
-            Token::Open(_) => {
-                local_level += 1;
-            }
-            Token::Close(_) => {
-                local_level -= 1;
-            }
-            _ => {}
+impl CellBits {
+    fn width(self) -> CellWidth {
+        match self {
+            CellBits::B8 => CellWidth::of::<u8>(),
+            CellBits::B16 => CellWidth::of::<u16>(),
+            CellBits::B32 => CellWidth::of::<u32>(),
+            CellBits::B64 => CellWidth::of::<u64>(),
         }
     }
-    pos
 }
 
-/// Execute the code inside the following set of square brackets (in code) if the value at the memory address referenced by the data pointer is 0 i.e. '['
-/// And keep doing it over and over again until value at the pointed-to memory address is 0.
-fn jump_forward(state: &mut State, pos: usize) {
-    state.instptr = pos;
+impl From<Underflow> for UnderflowPolicy {
+    fn from(underflow: Underflow) -> Self {
+        match underflow {
+            Underflow::Error => UnderflowPolicy::Error,
+            Underflow::Wrap => UnderflowPolicy::Wrap,
+            Underflow::Grow => UnderflowPolicy::Grow,
+        }
+    }
 }
 
-///calculate the matching [ to a ]
-fn rev_ofset(state: &mut State, pos: usize) -> usize {
-    let mut pos = pos;
-    let mut local_level = 1;
-    while local_level != 0 {
-        pos -= 1;
-        match state.inst[pos] {
-            Token::Open(_) => {
-                local_level -= 1;
-            }
-            Token::Close(_) => {
-                local_level += 1;
-            }
-            _ => {}
+/// Load the program source from wherever the arguments point to
+fn read_source(args: &Args) -> std::io::Result<Vec<u8>> {
+    if let Some(code) = &args.execute {
+        return Ok(code.clone().into_bytes());
+    }
+    match args.program.as_deref() {
+        Some(path) if path.as_os_str() != "-" => std::fs::read(path),
+        _ => {
+            let mut source = Vec::new();
+            std::io::stdin().read_to_end(&mut source)?;
+            Ok(source)
         }
     }
-    pos
 }
 
-/// Signify the end of a repeated code section i.e. ']'
-fn jump_rev(state: &mut State, pos: usize) {
-    state.instptr = pos;
-}
+fn main() -> ExitCode {
+    let args = Args::parse();
 
-fn main() {
-    let hello = include_str!("../hello.bf").as_bytes();
-    let mut program = State::new();
-    let mut curr: usize = 0;
+    let source = match read_source(&args) {
+        Ok(source) => source,
+        Err(err) => {
+            eprintln!("error: failed to read program: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let input: Box<dyn Read> = match &args.input {
+        Some(path) => match File::open(path) {
+            Ok(file) => Box::new(BufReader::new(file)),
+            Err(err) => {
+                eprintln!("error: failed to open input {}: {err}", path.display());
+                return ExitCode::FAILURE;
+            }
+        },
+        None => Box::new(std::io::stdin()),
+    };
 
-    for i in hello {
-        match *i {
-            b'>' => program.inst.push(Token::Right(1)),
-            b'<' => program.inst.push(Token::Left(1)),
-            b'+' => program.inst.push(Token::Incriment(1)),
-            b'-' => program.inst.push(Token::Decriment(1)),
-            b'.' => program.inst.push(Token::Output),
-            b',' => program.inst.push(Token::Input),
-            b'[' => program.inst.push(Token::Open(1)),
-            b']' => program.inst.push(Token::Close(1)),
-            _ => {continue;}
+    let mut program = match Program::parse(&source) {
+        Ok(program) => program,
+        Err(err) => {
+            eprintln!("error: {err}");
+            eprint!("{}", err.snippet(&source));
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut optimizer = Optimizer {
+        cell_width: args.cell_bits.width(),
+        fresh_machine: true,
+        tape_len: matches!(args.underflow, Underflow::Wrap).then_some(args.tape_len.max(1)),
+        ..Optimizer::level(args.opt_level)
+    };
+    for &(pass, enabled) in &args.passes {
+        if enabled {
+            optimizer.enable(pass);
+        } else {
+            optimizer.disable(pass);
         }
-        curr += 1;
     }
-    program.last = curr;
-    program.memory.push(0);
-
-    let mut new_inst: Vec<Token> = Vec::with_capacity(4096);
-    
-    for i in 0..program.last {
-        match program.inst[i] {
-            Token::Right(_) => {
-                if new_inst.len() != 0 {
-                    let val = new_inst[new_inst.len()-1];
-                    match val {
-                        Token::Right(b) => {
-                            let pos = new_inst.len()-1;
-                            new_inst[pos] = Token::Right(b+1);
-                        },
-                        _ => new_inst.push(Token::Right(1)),
-                    }
-                } else {
-                    new_inst.push(Token::Right(1));
-                }
-            },
-            Token::Left(_) => {
-                if new_inst.len() != 0 {
-                    let val = new_inst[new_inst.len()-1];
-                    match val {
-                        Token::Left(b) => {
-                            let pos = new_inst.len()-1;
-                            new_inst[pos] = Token::Left(b+1);
-                        },
-                        _ => new_inst.push(Token::Left(1)),
-                    }
-                } else {
-                    new_inst.push(Token::Left(1));
-                }
-            },
-            Token::Incriment(_) => {
-                if new_inst.len() != 0 {
-                    let val = new_inst[new_inst.len()-1];
-                    match val {
-                        Token::Incriment(b) => {
-                            let pos = new_inst.len()-1;
-                            new_inst[pos] = Token::Incriment(b.wrapping_add(1));
-                        },
-                        _ => new_inst.push(Token::Incriment(1)),
-                    }
-                } else {
-                    new_inst.push(Token::Incriment(1));
-                }
-            },
-            Token::Decriment(_) => {
-                if new_inst.len() != 0 {
-                    let val = new_inst[new_inst.len()-1];
-                    match val {
-                        Token::Decriment(b) => {
-                            let pos = new_inst.len()-1;
-                            new_inst[pos] = Token::Decriment(b.wrapping_add(1));
-                        },
-                        _ => new_inst.push(Token::Decriment(1)),
-                    }
-                } else {
-                    new_inst.push(Token::Decriment(1));
+    if args.emit == Some(Emit::Ir) {
+        let mut stdout = std::io::stdout().lock();
+        let mut result = write!(stdout, "; parsed\n{program}");
+        optimizer.run_inspect(&mut program, |pass, program| {
+            if result.is_ok() {
+                result = write!(stdout, "\n; after {}\n{program}", pass.name());
+            }
+        });
+        return report_emitted(result.and_then(|()| stdout.flush()));
+    }
+    optimizer.run(&mut program);
+    let config = Config {
+        underflow: args.underflow.into(),
+        eof: args.eof.into(),
+        output: if args.text {
+            OutputMode::Text
+        } else {
+            OutputMode::Bytes
+        },
+        tape_len: args.tape_len,
+    };
+    match args.emit {
+        Some(Emit::C) => {
+            let source = match args.cell_bits {
+                CellBits::B8 => emit::c::<u8>(&program, &config),
+                CellBits::B16 => emit::c::<u16>(&program, &config),
+                CellBits::B32 => emit::c::<u32>(&program, &config),
+                CellBits::B64 => emit::c::<u64>(&program, &config),
+            };
+            return report_emitted(write_stdout(source.as_bytes()));
+        }
+        Some(Emit::Rust) => {
+            let source = match args.cell_bits {
+                CellBits::B8 => emit::rust::<u8>(&program, &config),
+                CellBits::B16 => emit::rust::<u16>(&program, &config),
+                CellBits::B32 => emit::rust::<u32>(&program, &config),
+                CellBits::B64 => emit::rust::<u64>(&program, &config),
+            };
+            return report_emitted(write_stdout(source.as_bytes()));
+        }
+        #[cfg(feature = "cranelift")]
+        Some(Emit::Object) => {
+            let object = match cranelift::object(&program, optimizer.cell_width.bits(), &config) {
+                Ok(object) => object,
+                Err(err) => {
+                    eprintln!("error: failed to compile program: {err}");
+                    return ExitCode::FAILURE;
                 }
-            },
-            _ => new_inst.push(program.inst[i]),
+            };
+            return report_emitted(write_stdout(&object));
+        }
+        Some(Emit::Ir) | None => {}
+    }
+    let backend = Backend::of(&args);
+    let result = match args.cell_bits {
+        CellBits::B8 => run::<u8>(&program, config, input, backend),
+        CellBits::B16 => run::<u16>(&program, config, input, backend),
+        CellBits::B32 => run::<u32>(&program, config, input, backend),
+        CellBits::B64 => run::<u64>(&program, config, input, backend),
+    };
+    if std::io::stdout().is_terminal() {
+        println!();
+    }
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(Failure::Backend(message)) => {
+            eprintln!("error: {message}");
+            ExitCode::FAILURE
         }
+        Err(Failure::Run(err)) => {
+            eprintln!("error: {err}");
+            eprint!("{}", err.snippet(&source));
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Why a program couldn't be run to the end
+#[derive(Debug)]
+enum Failure {
+    /// The backend couldn't run it at all
+    Backend(String),
+    /// It stopped with an error
+    Run(RuntimeError),
+}
+
+impl From<RuntimeError> for Failure {
+    fn from(err: RuntimeError) -> Self {
+        Failure::Run(err)
     }
+}
 
-    program.inst = new_inst;
+/// Write all of `bytes` to stdout and flush it
+fn write_stdout(bytes: &[u8]) -> io::Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    stdout.write_all(bytes)?;
+    stdout.flush()
+}
 
-    for i in 0..program.inst.len() {
-        match program.inst[i] {
-            Token::Open(_) => {
-                let pos = forward_ofset(&mut program, i);
-                program.inst[i] = Token::Open(pos);
-            },
-            Token::Close(_) => {
-                let pos = rev_ofset(&mut program, i);
-                program.inst[i] = Token::Close(pos);
-            },
-            _ => {},
+/// Exit status once the program has been written out in another form,
+/// reporting why that failed like a failed run would
+fn report_emitted(result: io::Result<()>) -> ExitCode {
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: failed to write output: {}", err.kind());
+            ExitCode::FAILURE
         }
     }
+}
 
-    while program.instptr < program.inst.len() {
-        match program.inst[program.instptr] {
-            Token::Right(a) => inc_data(&mut program, a),
-            Token::Left(a) => dec_data(&mut program, a),
-            Token::Incriment(a) => incbyte(&mut program, a),
-            Token::Decriment(a) => decbyte(&mut program, a),
-            Token::Output => outbyte(&mut program),
-            Token::Input => inbyte(&mut program),
-            Token::Open(a) => {
-                if program.memory[program.memptr] == 0 {
-                    jump_forward(&mut program, a);
-                }
-            }
-            Token::Close(a) => {
-                if program.memory[program.memptr] != 0 {
-                    jump_rev(&mut program, a);
-                    continue;
-                }
-            }
+/// Execute `program` with cells of type `C` on the given backend, writing its
+/// output to stdout
+fn run<C: Cell>(
+    program: &Program,
+    config: Config,
+    input: impl Read,
+    backend: Backend,
+) -> Result<(), Failure> {
+    let output = BufWriter::new(std::io::stdout().lock());
+    match backend {
+        Backend::Interpreter => Ok(Machine::<C>::with_config(config).run(program, input, output)?),
+        Backend::Jit => run_jit::<C>(program, config, input, output),
+        #[cfg(feature = "cranelift")]
+        Backend::Cranelift => {
+            let compiled = cranelift::Compiled::<C>::compile(program, config)
+                .map_err(|err| Failure::Backend(format!("failed to compile program: {err}")))?;
+            Ok(compiled.run(input, output)?)
         }
-        program.instptr += 1;
     }
-    println!();
+}
+
+/// Compile `program` with the JIT and run it
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn run_jit<C: Cell>(
+    program: &Program,
+    config: Config,
+    input: impl Read,
+    output: impl Write,
+) -> Result<(), Failure> {
+    let jit = stupidfuck::Jit::<C>::compile(program, config)
+        .map_err(|err| Failure::Backend(format!("failed to compile program: {err}")))?;
+    Ok(jit.run(input, output)?)
+}
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+fn run_jit<C: Cell>(
+    _program: &Program,
+    _config: Config,
+    _input: impl Read,
+    _output: impl Write,
+) -> Result<(), Failure> {
+    Err(Failure::Backend(
+        "--jit is only supported on x86-64 Linux".to_string(),
+    ))
 }
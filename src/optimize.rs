@@ -0,0 +1,802 @@
+//! Passes that rewrite a program's instructions into faster equivalents.
+//!
+//! Every pass takes the instructions of a program and returns new ones; the
+//! brackets are linked again by the caller afterwards, so passes don't need to
+//! keep `Open`/`Close` positions up to date. An [`Optimizer`] runs a list of
+//! them over a [`Program`].
+//!
+//! Values in tokens wrap around at 64 bits. Passes that know the cell width
+//! keep them sign extended from that width, so -1 is always `u64::MAX` and
+//! values that are 0 modulo the width are recognised as doing nothing.
+
+use std::collections::HashSet;
+
+use crate::cell::{Cell, CellWidth};
+use crate::machine::{Config, UnderflowPolicy};
+use crate::program::{Prelude, Program};
+use crate::token::{Instruction, Span, Token};
+
+/// A single optimization, rewriting part of a program into something faster
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Pass {
+    /// Merge runs going the same way, like `+++` or `>>`; see [`merge_runs`]
+    Merge,
+    /// Also merge runs going opposite ways, like `+-` or `><`; see [`cancel_runs`]
+    Cancel,
+    /// Replace clear loops like `[-]` with [`Token::Set`]
+    Clear,
+    /// Replace multiply loops like `[->++<]` with [`Token::MulAdd`]
+    Mul,
+    /// Replace scan loops like `[>]` with [`Token::ScanRight`] and [`Token::ScanLeft`]
+    Scan,
+    /// Remove loops that can never run and clears of cells that are already zero
+    DeadCode,
+    /// Turn straight-line code into additions at offsets from the data pointer
+    Offset,
+    /// Run the program ahead of time up to its first `,`, see [`Prelude`];
+    /// only done for a fresh machine, see [`Optimizer::fresh_machine`]
+    Fold,
+}
+
+impl Pass {
+    /// Every pass, in the order the [`Optimizer`] runs them by default
+    pub const ALL: [Pass; 8] = [
+        Pass::Merge,
+        Pass::Cancel,
+        Pass::Clear,
+        Pass::Mul,
+        Pass::Scan,
+        Pass::DeadCode,
+        Pass::Offset,
+        Pass::Fold,
+    ];
+
+    /// The passes making up optimization level `level`, i.e. `-O2`
+    ///
+    /// Level 0 does nothing, 1 merges runs, 2 also replaces and removes loops
+    /// and 3 (or above) runs every pass.
+    pub fn level(level: u8) -> &'static [Pass] {
+        match level {
+            0 => &[],
+            1 => &Pass::ALL[..2],
+            2 => &Pass::ALL[..6],
+            _ => &Pass::ALL,
+        }
+    }
+
+    /// Short lowercase name of the pass, i.e. "mul"
+    pub fn name(self) -> &'static str {
+        match self {
+            Pass::Merge => "merge",
+            Pass::Cancel => "cancel",
+            Pass::Clear => "clear",
+            Pass::Mul => "mul",
+            Pass::Scan => "scan",
+            Pass::DeadCode => "dead",
+            Pass::Offset => "offset",
+            Pass::Fold => "fold",
+        }
+    }
+
+    /// Rewrite `program` for the machine `optimizer` targets
+    fn run(self, program: &mut Program, optimizer: &Optimizer) {
+        match self {
+            Pass::Merge => program.rewrite(|inst| merge_runs(inst, optimizer.cell_width)),
+            Pass::Cancel => program.rewrite(|inst| cancel_runs(inst, optimizer.cell_width)),
+            Pass::Clear => program.rewrite(clear_loops),
+            Pass::Mul => program.rewrite(|inst| mul_loops(inst, optimizer.tape_len)),
+            Pass::Scan => program.rewrite(scan_loops),
+            Pass::DeadCode => {
+                let start = optimizer.fresh_machine.then(|| program.prelude().clone());
+                program.rewrite(|inst| dead_code(inst, start.as_ref(), optimizer.tape_len));
+            }
+            Pass::Offset => program.rewrite(offset_ops),
+            Pass::Fold => fold_prefix(program, optimizer),
+        }
+    }
+}
+
+/// Runs a list of passes over programs
+///
+/// ```
+/// use stupidfuck::{CellWidth, Optimizer, Pass, Program, Token};
+///
+/// let mut program = Program::parse(b"+++[->++<]")?;
+/// let mut optimizer = Optimizer {
+///     cell_width: CellWidth::of::<u16>(),
+///     ..Optimizer::level(2)
+/// };
+/// optimizer.disable(Pass::Mul);
+/// optimizer.run(&mut program);
+/// let tokens: Vec<Token> = program.instructions().iter().map(|inst| inst.token).collect();
+/// assert!(!tokens.iter().any(|token| matches!(token, Token::MulAdd { .. })));
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Optimizer {
+    /// Passes to run, in order
+    pub passes: Vec<Pass>,
+    /// Width of the cells the program will run on
+    ///
+    /// Using 64 bits gives a program that runs correctly whatever the width,
+    /// unless it's folded for a fresh machine, since [`Pass::Fold`] works out
+    /// the values of cells exactly this wide.
+    pub cell_width: CellWidth,
+    /// Length of the tape if it wraps around, as with [`UnderflowPolicy::Wrap`],
+    /// so cells that far apart are the same one
+    pub tape_len: Option<usize>,
+    /// Most instructions [`Pass::Fold`] runs ahead of time
+    pub fold_steps: usize,
+    /// Whether the program will only run on a fresh [`Machine`], with every
+    /// cell zero, rather than after other programs on the same one
+    ///
+    /// [`Pass::DeadCode`] can then count on the cells being zero at the start,
+    /// and [`Pass::Fold`] only runs with it on. Off by default; a program
+    /// optimized with it on has to be run on a fresh machine.
+    ///
+    /// [`Machine`]: crate::Machine
+    pub fresh_machine: bool,
+}
+
+impl Optimizer {
+    /// An optimizer running the passes of [`Pass::level`]
+    pub fn level(level: u8) -> Self {
+        Optimizer {
+            passes: Pass::level(level).to_vec(),
+            cell_width: CellWidth::of::<u64>(),
+            tape_len: None,
+            fold_steps: 1_000_000,
+            fresh_machine: false,
+        }
+    }
+
+    /// An optimizer running every pass for cells of type `C`
+    pub fn for_cell<C: Cell>() -> Self {
+        Optimizer {
+            cell_width: CellWidth::of::<C>(),
+            ..Optimizer::default()
+        }
+    }
+
+    /// An optimizer running every pass for a machine with cells of type `C`
+    /// and the given configuration
+    pub fn for_config<C: Cell>(config: &Config) -> Self {
+        Optimizer {
+            tape_len: (config.underflow == UnderflowPolicy::Wrap).then_some(config.tape_len.max(1)),
+            ..Optimizer::for_cell::<C>()
+        }
+    }
+
+    /// Run `pass` as well, in its usual place in [`Pass::ALL`]
+    pub fn enable(&mut self, pass: Pass) {
+        if !self.passes.contains(&pass) {
+            self.passes.push(pass);
+            self.passes.sort();
+        }
+    }
+
+    /// Stop running `pass`
+    pub fn disable(&mut self, pass: Pass) {
+        self.passes.retain(|&p| p != pass);
+    }
+
+    /// Run every pass over `program`, one after the other
+    pub fn run(&self, program: &mut Program) {
+        self.run_inspect(program, |_, _| {});
+    }
+
+    /// Like [`Optimizer::run`], calling `inspect` with the program after each pass
+    pub fn run_inspect(&self, program: &mut Program, mut inspect: impl FnMut(Pass, &Program)) {
+        for &pass in &self.passes {
+            pass.run(program, self);
+            inspect(pass, program);
+        }
+    }
+}
+
+impl Default for Optimizer {
+    fn default() -> Self {
+        Optimizer::level(3)
+    }
+}
+
+/// Merge each run of moves going the same way into one [`Token::Move`] and each
+/// run of additions going the same way to the current cell into one
+/// [`Token::Add`], e.g. `+++` becomes `Add(3)` and `<<` becomes `Move(-2)`.
+///
+/// Additions are done modulo 2^`width`, so with 8-bit cells 256 `+` in a
+/// row vanish completely. The merged instruction's span covers every character
+/// it was made from.
+pub fn merge_runs(inst: Vec<Instruction>, width: CellWidth) -> Vec<Instruction> {
+    merge(inst, width.bits(), false)
+}
+
+/// Like [`merge_runs`], but also let runs going opposite ways cancel out, e.g.
+/// `++-` becomes `Add(1)` and `>+-<` disappears completely.
+///
+/// Moves like `<>` are kept apart, so moving off the start of the tape is still
+/// caught.
+pub fn cancel_runs(inst: Vec<Instruction>, width: CellWidth) -> Vec<Instruction> {
+    merge(inst, width.bits(), true)
+}
+
+/// Merge runs for [`merge_runs`] and, if `cancel` is set, [`cancel_runs`]
+fn merge(inst: Vec<Instruction>, cell_bits: u32, cancel: bool) -> Vec<Instruction> {
+    let mut new_inst: Vec<Instruction> = Vec::with_capacity(inst.len());
+    // which way the run merged into the last instruction went, which isn't
+    // always the sign of its value once that has wrapped around
+    let mut run_way = 0;
+    for instruction in inst {
+        let token = match instruction.token {
+            Token::Add { offset, value } => Token::Add {
+                offset,
+                value: wrap(value, cell_bits),
+            },
+            token => token,
+        };
+        let same_way = cancel || way(token) == run_way;
+        let merged = match (new_inst.last().map(|last| last.token), token) {
+            (Some(Token::Move(b)), Token::Move(a)) if same_way && b >= (b + a).min(0) => {
+                Token::Move(b + a)
+            }
+            (
+                Some(Token::Add {
+                    offset: 0,
+                    value: b,
+                }),
+                Token::Add {
+                    offset: 0,
+                    value: a,
+                },
+            ) if same_way => Token::Add {
+                offset: 0,
+                value: wrap(b.wrapping_add(a), cell_bits),
+            },
+            _ => {
+                if !is_noop(token) {
+                    new_inst.push(Instruction::new(token, instruction.span));
+                    run_way = way(token);
+                }
+                continue;
+            }
+        };
+        let last = new_inst.pop().expect("merged with the last instruction");
+        if is_noop(merged) {
+            run_way = new_inst.last().map_or(0, |last| way(last.token));
+        } else {
+            new_inst.push(Instruction::new(merged, last.span.to(instruction.span)));
+        }
+    }
+    new_inst
+}
+
+/// The sign of a move or of an addition to the current cell, 0 for anything else
+fn way(token: Token) -> i64 {
+    match token {
+        Token::Move(amount) => amount.signum() as i64,
+        Token::Add { offset: 0, value } => (value as i64).signum(),
+        _ => 0,
+    }
+}
+
+/// `value` modulo 2^`bits`, sign extended back to 64 bits
+fn wrap(value: u64, bits: u32) -> u64 {
+    let shift = 64 - bits;
+    (((value << shift) as i64) >> shift) as u64
+}
+
+/// Whether `token` does nothing at all, like `Move(0)`
+fn is_noop(token: Token) -> bool {
+    matches!(token, Token::Move(0) | Token::Add { value: 0, .. })
+}
+
+/// Replace loops that do nothing but count the current cell down (or up) to
+/// zero, such as `[-]` and `[+]`, with `Set(0)`, and fold a `+` or `-` right
+/// after into the value being set.
+///
+/// Any odd step works, since repeatedly adding an odd number reaches zero from
+/// every starting value whatever the cell width. Even steps are left alone as
+/// they loop forever on odd values.
+fn clear_loops(inst: Vec<Instruction>) -> Vec<Instruction> {
+    let mut new_inst: Vec<Instruction> = Vec::with_capacity(inst.len());
+    for instruction in inst {
+        new_inst.push(instruction);
+        match last_tokens(&new_inst) {
+            [Some(Token::Open(_)), Some(Token::Add {
+                offset: 0,
+                value: step,
+            }), Some(Token::Close(_))]
+                if step % 2 == 1 =>
+            {
+                fold_tail(&mut new_inst, 3, Token::Set(0));
+            }
+            [_, Some(Token::Set(val)), Some(Token::Add { offset: 0, value })] => {
+                fold_tail(&mut new_inst, 2, Token::Set(val.wrapping_add(value)));
+            }
+            _ => {}
+        }
+    }
+    new_inst
+}
+
+/// The tokens of the last `N` instructions, oldest first, padded with `None`
+/// at the front if there aren't that many
+fn last_tokens<const N: usize>(inst: &[Instruction]) -> [Option<Token>; N] {
+    let mut tokens = [None; N];
+    let tail = &inst[inst.len().saturating_sub(N)..];
+    for (slot, instruction) in tokens[N - tail.len()..].iter_mut().zip(tail) {
+        *slot = Some(instruction.token);
+    }
+    tokens
+}
+
+/// Replace the last `count` instructions with a single `token`, spanning all of them
+fn fold_tail(inst: &mut Vec<Instruction>, count: usize, token: Token) {
+    let span = inst[inst.len() - count..]
+        .iter()
+        .map(|instruction| instruction.span)
+        .reduce(Span::to)
+        .expect("folding at least one instruction");
+    inst.truncate(inst.len() - count);
+    inst.push(Instruction::new(token, span));
+}
+
+/// Replace loops that only move the data pointer, like `[>]` or `[<<]`, with
+/// [`Token::ScanRight`] and [`Token::ScanLeft`].
+fn scan_loops(inst: Vec<Instruction>) -> Vec<Instruction> {
+    let mut new_inst: Vec<Instruction> = Vec::with_capacity(inst.len());
+    for instruction in inst {
+        new_inst.push(instruction);
+        if let [Some(Token::Open(_)), Some(Token::Move(stride)), Some(Token::Close(_))] =
+            last_tokens(&new_inst)
+        {
+            let scan = if stride > 0 {
+                Token::ScanRight(stride as usize)
+            } else {
+                Token::ScanLeft(stride.unsigned_abs())
+            };
+            fold_tail(&mut new_inst, 3, scan);
+        }
+    }
+    new_inst
+}
+
+/// Replace balanced loops that only add constants to nearby cells and count
+/// the current cell down (or up) by one, like `[->+>++<<]`, with a
+/// [`Token::MulAdd`] for every cell they change followed by `Set(0)`.
+///
+/// Loops that wander further left than the cells they change are kept, so
+/// moving off the start of the tape is still caught, and so are loops that
+/// change the same cell twice once a wrapping tape of `tape_len` cells comes
+/// back around.
+fn mul_loops(inst: Vec<Instruction>, tape_len: Option<usize>) -> Vec<Instruction> {
+    let mut new_inst: Vec<Instruction> = Vec::with_capacity(inst.len());
+    // positions in `new_inst` of the loops that are still open
+    let mut opens = Vec::new();
+    for instruction in inst {
+        new_inst.push(instruction);
+        match instruction.token {
+            Token::Open(_) => opens.push(new_inst.len() - 1),
+            Token::Close(_) => {
+                let start = opens.pop().expect("brackets are balanced");
+                let body = &new_inst[start + 1..new_inst.len() - 1];
+                if let Some(changes) = mul_loop(body, tape_len) {
+                    let span = new_inst[start].span.to(instruction.span);
+                    new_inst.truncate(start);
+                    for (offset, factor) in changes {
+                        new_inst.push(Instruction::new(Token::MulAdd { offset, factor }, span));
+                    }
+                    new_inst.push(Instruction::new(Token::Set(0), span));
+                }
+            }
+            _ => {}
+        }
+    }
+    new_inst
+}
+
+/// The `(offset, factor)` of every cell a multiply loop with the given body
+/// changes, or `None` if it isn't one
+fn mul_loop(body: &[Instruction], tape_len: Option<usize>) -> Option<Vec<(isize, u64)>> {
+    let mut offset: isize = 0;
+    let mut leftmost: isize = 0;
+    // total added to each cell per iteration, in order of first change
+    let mut deltas: Vec<(isize, u64)> = Vec::new();
+    for instruction in body {
+        let (at, delta) = match instruction.token {
+            Token::Move(a) => {
+                offset += a;
+                leftmost = leftmost.min(offset);
+                continue;
+            }
+            Token::Add { offset: at, value } => (offset + at, value),
+            _ => return None,
+        };
+        leftmost = leftmost.min(at);
+        match deltas.iter_mut().find(|(changed, _)| *changed == at) {
+            Some((_, total)) => *total = total.wrapping_add(delta),
+            None => deltas.push((at, delta)),
+        }
+    }
+    if offset != 0 {
+        return None;
+    }
+    if let Some(len) = tape_len {
+        // cells a tape length apart are the same one, which may be the counter
+        let len = len.max(1) as isize;
+        let mut cells = HashSet::new();
+        for &(at, _) in &deltas {
+            if !cells.insert(at.rem_euclid(len)) {
+                return None;
+            }
+        }
+    }
+
+    // counting down by one runs the loop `cell` times, counting up by one runs
+    // it `-cell` times, so scale everything by minus the step
+    let step = deltas
+        .iter()
+        .find(|(at, _)| *at == 0)
+        .map(|&(_, step)| step);
+    let scale = match step {
+        Some(1) => u64::MAX,
+        Some(u64::MAX) => 1,
+        _ => return None,
+    };
+    let changes: Vec<(isize, u64)> = deltas
+        .into_iter()
+        .filter(|&(at, delta)| at != 0 && delta != 0)
+        .map(|(at, delta)| (at, delta.wrapping_mul(scale)))
+        .collect();
+    let leftmost_changed = changes.iter().map(|&(at, _)| at).min().unwrap_or(0);
+    if leftmost < leftmost_changed.min(0) {
+        return None;
+    }
+    Some(changes)
+}
+
+/// Remove loops that start on a cell known to be zero, like a comment loop at
+/// the very start of a program or a loop right after another one, along with
+/// [`Token::Set`]s to 0 and other instructions that do nothing on a zero cell.
+///
+/// Cells are known to be zero after a loop ends or `Set(0)`, and at the start
+/// if the program runs on a fresh machine with `prelude`, unless that set them,
+/// until something is added to them or read into them. Where the data pointer
+/// is after a scan isn't known, so the other cells aren't either.
+fn dead_code(
+    inst: Vec<Instruction>,
+    prelude: Option<&Prelude>,
+    tape_len: Option<usize>,
+) -> Vec<Instruction> {
+    let mut new_inst = Vec::with_capacity(inst.len());
+    // data pointer, relative to wherever it was when `zeros` was last reset
+    let mut pos: isize = 0;
+    let cell = |pos: isize, offset: isize| match tape_len {
+        // an empty tape still has a cell, like the machine's
+        Some(len) => (pos + offset).rem_euclid(len.max(1) as isize),
+        None => pos + offset,
+    };
+    let mut zeros = match prelude {
+        Some(prelude) => Zeros::AllBut(
+            (prelude.tape.iter().enumerate())
+                .filter(|(_, &value)| value != 0)
+                .map(|(number, _)| cell(0, number as isize - prelude.memptr as isize))
+                .collect(),
+        ),
+        None => Zeros::Only(HashSet::new()),
+    };
+    let mut inst = inst.into_iter();
+    while let Some(instruction) = inst.next() {
+        let current = cell(pos, 0);
+        if zeros.contains(current) {
+            match instruction.token {
+                Token::Open(_) => {
+                    let mut depth = 1;
+                    for skipped in inst.by_ref() {
+                        match skipped.token {
+                            Token::Open(_) => depth += 1,
+                            Token::Close(_) if depth == 1 => break,
+                            Token::Close(_) => depth -= 1,
+                            _ => {}
+                        }
+                    }
+                    continue;
+                }
+                Token::Set(0) | Token::MulAdd { .. } | Token::ScanRight(_) | Token::ScanLeft(_) => {
+                    continue
+                }
+                _ => {}
+            }
+        }
+        match instruction.token {
+            Token::Move(amount) => pos += amount,
+            Token::Add { value: 0, .. } => {}
+            Token::Add { offset, .. } | Token::MulAdd { offset, .. } => {
+                zeros.forget(cell(pos, offset));
+            }
+            Token::Set(0) => zeros.insert(current),
+            Token::Set(_) | Token::Input => zeros.forget(current),
+            Token::ScanRight(_) | Token::ScanLeft(_) | Token::Close(_) => {
+                pos = 0;
+                zeros = Zeros::only(cell(pos, 0));
+            }
+            Token::Open(_) => {
+                pos = 0;
+                zeros = Zeros::Only(HashSet::new());
+            }
+            Token::Output => {}
+        }
+        new_inst.push(instruction);
+    }
+    new_inst
+}
+
+/// The cells known to be zero, for [`dead_code`]
+enum Zeros {
+    /// Every cell but these
+    AllBut(HashSet<isize>),
+    /// Only these cells
+    Only(HashSet<isize>),
+}
+
+impl Zeros {
+    fn only(cell: isize) -> Self {
+        Zeros::Only(HashSet::from([cell]))
+    }
+
+    fn contains(&self, cell: isize) -> bool {
+        match self {
+            Zeros::AllBut(cells) => !cells.contains(&cell),
+            Zeros::Only(cells) => cells.contains(&cell),
+        }
+    }
+
+    fn insert(&mut self, cell: isize) {
+        match self {
+            Zeros::AllBut(cells) => cells.remove(&cell),
+            Zeros::Only(cells) => cells.insert(cell),
+        };
+    }
+
+    fn forget(&mut self, cell: isize) {
+        match self {
+            Zeros::AllBut(cells) => cells.insert(cell),
+            Zeros::Only(cells) => cells.remove(&cell),
+        };
+    }
+}
+
+/// Rewrite every straight-line run of moves and additions into a [`Token::Add`]
+/// for each cell it changes, relative to where the run started, followed by a
+/// single move for the whole run, e.g. `>>+++<<-` becomes
+/// `Add { offset: 2, value: 3 }, Add { offset: 0, value: -1 }`.
+///
+/// Runs that wander further left than the cells they change or end up on are
+/// kept, so moving off the start of the tape is still caught.
+fn offset_ops(inst: Vec<Instruction>) -> Vec<Instruction> {
+    let mut new_inst: Vec<Instruction> = Vec::with_capacity(inst.len());
+    let mut block: Vec<Instruction> = Vec::new();
+    for instruction in inst {
+        match instruction.token {
+            Token::Move(_) | Token::Add { .. } => block.push(instruction),
+            _ => {
+                offset_block(&mut new_inst, &mut block);
+                new_inst.push(instruction);
+            }
+        }
+    }
+    offset_block(&mut new_inst, &mut block);
+    new_inst
+}
+
+/// Rewrite a straight-line run for [`offset_ops`], pushing the result onto
+/// `new_inst` and leaving `block` empty
+fn offset_block(new_inst: &mut Vec<Instruction>, block: &mut Vec<Instruction>) {
+    let mut offset: isize = 0;
+    let mut leftmost: isize = 0;
+    let mut moves: Option<Span> = None;
+    // total added to each cell, in order of first change
+    let mut adds: Vec<(isize, u64, Span)> = Vec::new();
+    for instruction in block.iter() {
+        let (at, value) = match instruction.token {
+            Token::Move(a) => {
+                offset += a;
+                leftmost = leftmost.min(offset);
+                moves = Some(moves.map_or(instruction.span, |span| span.to(instruction.span)));
+                continue;
+            }
+            Token::Add { offset: at, value } => (offset + at, value),
+            _ => unreachable!("only moves and additions are collected"),
+        };
+        leftmost = leftmost.min(at);
+        match adds.iter_mut().find(|(changed, _, _)| *changed == at) {
+            Some((_, total, span)) => {
+                *total = total.wrapping_add(value);
+                *span = span.to(instruction.span);
+            }
+            None => adds.push((at, value, instruction.span)),
+        }
+    }
+    adds.retain(|&(_, value, _)| value != 0);
+
+    let leftmost_used = adds
+        .iter()
+        .map(|&(at, _, _)| at)
+        .fold(offset.min(0), isize::min);
+    if leftmost < leftmost_used {
+        new_inst.append(block);
+        return;
+    }
+    for (at, value, span) in adds {
+        new_inst.push(Instruction::new(Token::Add { offset: at, value }, span));
+    }
+    if let Some(span) = moves.filter(|_| offset != 0) {
+        new_inst.push(Instruction::new(Token::Move(offset), span));
+    }
+    block.clear();
+}
+
+/// Cells past this aren't used when running a program ahead of time
+const FOLD_TAPE_LEN: usize = 1 << 16;
+
+/// Run the start of `program` ahead of time, as far as it goes without reading
+/// input, and replace it with a [`Prelude`] leaving the machine in the same state.
+///
+/// Running stops at the first `,`, after [`Optimizer::fold_steps`] instructions,
+/// or before the data pointer leaves the start of the tape or goes past the
+/// first [`FOLD_TAPE_LEN`] cells (or past the end, if the tape wraps), which
+/// is left for the machine to deal with. When it stops inside a loop, the
+/// prelude ends right before the outermost loop instead.
+///
+/// Nothing is folded unless the program runs on a fresh machine, as the
+/// prelude is only right for an all zero tape.
+fn fold_prefix(program: &mut Program, optimizer: &Optimizer) {
+    if !optimizer.fresh_machine {
+        return;
+    }
+    let inst = program.instructions();
+    let mut depths = Vec::with_capacity(inst.len());
+    let mut depth = 0;
+    // how many loops each instruction is in, counting a ']' as part of its loop
+    for instruction in inst {
+        depths.push(depth);
+        match instruction.token {
+            Token::Open(_) => depth += 1,
+            Token::Close(_) => depth -= 1,
+            _ => {}
+        }
+    }
+
+    let mut folded = Fold::new(program.prelude(), optimizer);
+    let (pc, loop_start) = folded.run(inst, &depths, optimizer.fold_steps);
+    if depths.get(pc).is_some_and(|&depth| depth > 0) {
+        // running the same steps again stops right before the loop
+        folded = Fold::new(program.prelude(), optimizer);
+        folded.run(inst, &depths, loop_start);
+    }
+    let pc = folded.pc;
+    if pc == 0 {
+        return;
+    }
+    let mut prelude = folded.prelude;
+    let span = (inst[..pc].iter())
+        .map(|inst| inst.span)
+        .reduce(Span::to)
+        .expect("something was folded");
+    if *program.prelude() == Prelude::default() {
+        prelude.span = span;
+    } else {
+        prelude.span = prelude.span.to(span);
+    }
+    while prelude.tape.last() == Some(&0) {
+        prelude.tape.pop();
+    }
+    program.fold(pc, prelude);
+}
+
+/// A program being run ahead of time, for [`fold_prefix`]
+struct Fold {
+    prelude: Prelude,
+    /// Position of the next instruction to run
+    pc: usize,
+    mask: u64,
+    tape_len: usize,
+}
+
+impl Fold {
+    fn new(prelude: &Prelude, optimizer: &Optimizer) -> Self {
+        let tape_len = optimizer
+            .tape_len
+            .map_or(FOLD_TAPE_LEN, |len| len.max(1))
+            .min(FOLD_TAPE_LEN);
+        let mut prelude = prelude.clone();
+        prelude.tape.resize(tape_len.max(prelude.tape.len()), 0);
+        Fold {
+            prelude,
+            pc: 0,
+            mask: u64::MAX >> (64 - optimizer.cell_width.bits()),
+            tape_len,
+        }
+    }
+
+    /// Run instructions until it has to stop or `steps` have been run, leaving
+    /// `pc` at the instruction it stopped before, and return that along with
+    /// the number of steps run before the last instruction outside any loop
+    fn run(&mut self, inst: &[Instruction], depths: &[usize], steps: usize) -> (usize, usize) {
+        let mut loop_start = 0;
+        for step in 0..steps {
+            let Some(instruction) = inst.get(self.pc) else {
+                break;
+            };
+            if depths[self.pc] == 0 {
+                loop_start = step;
+            }
+            if !self.step(instruction.token) {
+                break;
+            }
+        }
+        (self.pc, loop_start)
+    }
+
+    /// Run a single instruction, or return false if it can't be
+    fn step(&mut self, token: Token) -> bool {
+        let memptr = self.prelude.memptr;
+        let tape = &mut self.prelude.tape;
+        let current = tape[memptr];
+        match token {
+            Token::Move(amount) => match self.cell(amount) {
+                Some(index) => self.prelude.memptr = index,
+                None => return false,
+            },
+            Token::Add { offset, value } => match self.cell(offset) {
+                Some(index) => {
+                    let tape = &mut self.prelude.tape;
+                    tape[index] = tape[index].wrapping_add(value) & self.mask;
+                }
+                None => return false,
+            },
+            Token::Set(value) => tape[memptr] = value & self.mask,
+            Token::MulAdd { .. } if current == 0 => {}
+            Token::MulAdd { offset, factor } => match self.cell(offset) {
+                Some(index) => {
+                    let tape = &mut self.prelude.tape;
+                    let product = current.wrapping_mul(factor);
+                    tape[index] = tape[index].wrapping_add(product) & self.mask;
+                }
+                None => return false,
+            },
+            Token::ScanRight(stride) | Token::ScanLeft(stride) => {
+                let stride = match token {
+                    Token::ScanRight(_) => stride as isize,
+                    _ => -(stride as isize),
+                };
+                let mut index = memptr;
+                while tape[index] != 0 {
+                    match index
+                        .checked_add_signed(stride)
+                        .filter(|&index| index < self.tape_len)
+                    {
+                        Some(next) => index = next,
+                        None => return false,
+                    }
+                }
+                self.prelude.memptr = index;
+            }
+            Token::Open(pos) if current == 0 => self.pc = pos,
+            Token::Close(pos) if current != 0 => self.pc = pos,
+            Token::Open(_) | Token::Close(_) => {}
+            Token::Output => self.prelude.output.push(current),
+            Token::Input => return false,
+        }
+        self.pc += 1;
+        true
+    }
+
+    /// Index of the cell `offset` cells away from the data pointer, if it's on
+    /// the part of the tape being used
+    fn cell(&self, offset: isize) -> Option<usize> {
+        (self.prelude.memptr)
+            .checked_add_signed(offset)
+            .filter(|&index| index < self.tape_len)
+    }
+}
@@ -0,0 +1,182 @@
+use std::fmt;
+
+use crate::cell::Cell;
+use crate::error::{ParseError, ParseErrorKind};
+use crate::optimize::Optimizer;
+use crate::token::{Instruction, Span, Token};
+
+/// A parsed brainfuck program, ready to be executed by a [`Machine`](crate::Machine)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Program {
+    /// All code (instruction data), with every '[' and ']' linked to its partner
+    inst: Vec<Instruction>,
+    /// What running the start of the program does, if that was done ahead of time
+    prelude: Prelude,
+}
+
+/// The state a fresh machine is left in by running the start of a program,
+/// worked out ahead of time by [`Pass::Fold`](crate::Pass::Fold)
+///
+/// A machine running the program sets up its tape like this and writes the
+/// output before executing the first instruction.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Prelude {
+    /// Values of the cells from the data pointer's starting cell onwards; the
+    /// rest are 0
+    pub tape: Vec<u64>,
+    /// Number of the cell the data pointer ends up on
+    pub memptr: usize,
+    /// Every cell value written out, in order
+    pub output: Vec<u64>,
+    /// Source code the prelude was worked out from
+    pub span: Span,
+}
+
+impl Prelude {
+    /// Whether the prelude doesn't do anything, as for a program that hasn't been folded
+    pub fn is_empty(&self) -> bool {
+        self.tape.iter().all(|&cell| cell == 0) && self.memptr == 0 && self.output.is_empty()
+    }
+}
+
+impl Program {
+    /// Tokenize brainfuck source code, skipping every character that isn't an instruction
+    ///
+    /// Fails if the brackets in `source` aren't balanced.
+    pub fn parse(source: &[u8]) -> Result<Self, ParseError> {
+        let mut inst = Vec::with_capacity(source.len());
+        for (offset, i) in source.iter().enumerate() {
+            let token = match *i {
+                b'>' => Token::Move(1),
+                b'<' => Token::Move(-1),
+                b'+' => Token::Add {
+                    offset: 0,
+                    value: 1,
+                },
+                b'-' => Token::Add {
+                    offset: 0,
+                    value: u64::MAX,
+                },
+                b'.' => Token::Output,
+                b',' => Token::Input,
+                b'[' => Token::Open(1),
+                b']' => Token::Close(1),
+                _ => {
+                    continue;
+                }
+            };
+            inst.push(Instruction::new(token, Span::new(offset, offset + 1)));
+        }
+        let mut program = Program {
+            inst,
+            prelude: Prelude::default(),
+        };
+        if let Err((kind, pos)) = link(&mut program.inst) {
+            return Err(ParseError::new(kind, source, program.inst[pos].span.start));
+        }
+        Ok(program)
+    }
+
+    /// Run every optimization pass over the program; see [`Optimizer`]
+    ///
+    /// The result runs correctly whatever the cell width, as long as the tape
+    /// doesn't wrap around; use [`Program::optimize_for`] to also simplify
+    /// arithmetic that only cancels out for a particular width, or
+    /// [`Optimizer::for_config`] for a machine with [`UnderflowPolicy::Wrap`].
+    ///
+    /// [`UnderflowPolicy::Wrap`]: crate::UnderflowPolicy::Wrap
+    pub fn optimize(&mut self) {
+        Optimizer::default().run(self);
+    }
+
+    /// Like [`Program::optimize`], for running on a machine with cells of type `C`
+    pub fn optimize_for<C: Cell>(&mut self) {
+        Optimizer::for_cell::<C>().run(self);
+    }
+
+    /// Replace the instructions with what `pass` makes of them, then link the
+    /// brackets again
+    pub(crate) fn rewrite(&mut self, pass: impl FnOnce(Vec<Instruction>) -> Vec<Instruction>) {
+        self.inst = pass(std::mem::take(&mut self.inst));
+        link(&mut self.inst).expect("optimizing keeps brackets balanced");
+    }
+
+    /// Replace the first `count` instructions with `prelude`, which covers
+    /// what they and the current prelude did
+    pub(crate) fn fold(&mut self, count: usize, prelude: Prelude) {
+        self.inst.drain(..count);
+        self.prelude = prelude;
+        link(&mut self.inst).expect("folding keeps brackets balanced");
+    }
+
+    /// All instructions making up the program
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.inst
+    }
+
+    /// What runs before the first instruction, see [`Prelude`]
+    pub fn prelude(&self) -> &Prelude {
+        &self.prelude
+    }
+}
+
+/// Disassembly of the program, one instruction per line with its index, the
+/// token indented by loop nesting and the span it came from, after the
+/// prelude if there is one:
+///
+/// ```text
+///    0  add 3                        0..3
+///    1  open -> 6                    3..4
+///    2    add -1                     4..5
+///    3    move 1                     5..6
+///    4    add 2                      6..8
+///    5    move -1                    8..9
+///    6  close -> 1                   9..10
+/// ```
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.prelude.is_empty() {
+            let output: Vec<u8> = self.prelude.output.iter().map(|&cell| cell as u8).collect();
+            writeln!(f, "{:>4}  {:<28} {}", "-", "prelude", self.prelude.span)?;
+            writeln!(f, "        tape {:?}", self.prelude.tape)?;
+            writeln!(f, "        memptr {}", self.prelude.memptr)?;
+            writeln!(f, "        output \"{}\"", output.escape_ascii())?;
+        }
+        let mut depth = 0;
+        for (pos, inst) in self.inst.iter().enumerate() {
+            if let Token::Close(_) = inst.token {
+                depth -= 1;
+            }
+            let token = format!("{:indent$}{}", "", inst.token, indent = depth * 2);
+            writeln!(f, "{pos:>4}  {token:<28} {}", inst.span)?;
+            if let Token::Open(_) = inst.token {
+                depth += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Point every '[' at its matching ']' and vice versa, in a single pass
+///
+/// Fails with the position of the first ']' that has no partner, or else of
+/// the first '[' that is never closed.
+fn link(inst: &mut [Instruction]) -> Result<(), (ParseErrorKind, usize)> {
+    // positions of the '['s still waiting for their ']', innermost last
+    let mut opens = Vec::new();
+    for pos in 0..inst.len() {
+        match inst[pos].token {
+            Token::Open(_) => opens.push(pos),
+            Token::Close(_) => {
+                let open = opens.pop().ok_or((ParseErrorKind::UnmatchedClose, pos))?;
+                inst[open].token = Token::Open(pos);
+                inst[pos].token = Token::Close(open);
+            }
+            _ => {}
+        }
+    }
+    match opens.first() {
+        Some(&pos) => Err((ParseErrorKind::UnmatchedOpen, pos)),
+        None => Ok(()),
+    }
+}
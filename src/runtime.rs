@@ -0,0 +1,214 @@
+//! What compiled programs run on: a tape they work on directly, and callbacks
+//! for everything else.
+//!
+//! Compiled code gets a pointer to a [`State`] and keeps the tape's address,
+//! the data pointer and the tape's length from it wherever it likes. Every
+//! access off the cells the tape has so far goes through [`reach`], which
+//! grows the tape or stops the program with a [`RuntimeError`] as the
+//! [`UnderflowPolicy`] says, and input and output go through [`input`] and
+//! [`output`] into the [`Read`]/[`Write`] the program was run with. Callbacks
+//! return nonzero if the program has to stop, and the compiled code then
+//! returns straight away. Callbacks may move the tape, so the compiled code
+//! has to store the data pointer before calling one and load all three fields
+//! again afterwards.
+
+use std::io::{Read, Write};
+
+use crate::cell::Cell;
+use crate::error::RuntimeError;
+use crate::machine::{self, Config, EofPolicy, UnderflowPolicy};
+use crate::program::Prelude;
+use crate::tape::Tape;
+use crate::token::Span;
+
+/// A compiled program, taking a pointer to its [`State`] and returning nonzero
+/// if it failed
+pub(crate) type Entry = unsafe extern "C" fn(*mut u8) -> u64;
+
+/// Run the compiled program `entry` on a fresh tape like
+/// [`Machine::run`](crate::Machine::run) would, after setting up the tape as
+/// its prelude says
+///
+/// # Safety
+///
+/// `entry` has to be compiled for cells of type `C` and follow the rules in
+/// the [module docs](self).
+pub(crate) unsafe fn run<C: Cell, R: Read, W: Write>(
+    entry: Entry,
+    spans: &[Span],
+    prelude: &Prelude,
+    config: Config,
+    mut input: R,
+    mut output: W,
+) -> Result<(), RuntimeError> {
+    let tape = match config.underflow {
+        UnderflowPolicy::Wrap => Tape::new(config.tape_len.max(1)),
+        _ => Tape::new(1),
+    };
+    let mut state = State {
+        cells: std::ptr::null_mut(),
+        memptr: 0,
+        len: 0,
+        tape,
+        config,
+        input: &mut input,
+        output: &mut output,
+        spans,
+        error: None,
+    };
+    let result = state.run_prelude(prelude).and_then(|()| {
+        state.sync();
+        // SAFETY: the caller promises `entry` works on a `State<C>`
+        let failed = unsafe { entry(&mut state as *mut State<C> as *mut u8) };
+        match state.error.take() {
+            Some(err) => Err(err),
+            None => {
+                debug_assert_eq!(failed, 0);
+                Ok(())
+            }
+        }
+    });
+    let end = spans.last().map_or(0, |span| span.end);
+    let flushed = output.flush().map_err(|err| RuntimeError::Io {
+        pc: spans.len(),
+        span: Span::new(end, end),
+        kind: err.kind(),
+    });
+    result.and(flushed)
+}
+
+/// Everything the compiled code and its callbacks work on
+///
+/// The compiled code gets a pointer to this, and reads the first three fields
+/// at fixed offsets (see [`CELLS`], [`MEMPTR`] and [`LEN`]).
+#[repr(C)]
+pub(crate) struct State<'a, C: Cell> {
+    /// Address of the tape's first cell
+    cells: *mut C,
+    /// Data pointer, as an index into the tape
+    memptr: usize,
+    /// Number of cells on the tape
+    len: usize,
+    tape: Tape<C>,
+    config: Config,
+    input: &'a mut dyn Read,
+    output: &'a mut dyn Write,
+    spans: &'a [Span],
+    /// Why the program stopped, once a callback has failed
+    error: Option<RuntimeError>,
+}
+
+/// Offsets of the fields of [`State`] read by the compiled code
+pub(crate) const CELLS: u8 = 0;
+pub(crate) const MEMPTR: u8 = 8;
+pub(crate) const LEN: u8 = 16;
+
+impl<C: Cell> State<'_, C> {
+    /// Point the fields the compiled code reads at the tape again, after it
+    /// has grown
+    fn sync(&mut self) {
+        self.cells = self.tape.as_mut_ptr();
+        self.len = self.tape.len();
+    }
+
+    /// Set up the tape as the prelude left it and write out its output, like
+    /// the [`Machine`](crate::Machine) does
+    fn run_prelude(&mut self, prelude: &Prelude) -> Result<(), RuntimeError> {
+        for (number, &value) in prelude.tape.iter().enumerate() {
+            let index = self.right_of(number);
+            self.tape[index] = C::from_u64(value);
+        }
+        for &value in &prelude.output {
+            machine::write_cell(self.config.output, &mut *self.output, value).map_err(|err| {
+                RuntimeError::Io {
+                    pc: 0,
+                    span: prelude.span,
+                    kind: err.kind(),
+                }
+            })?;
+        }
+        self.memptr = self.right_of(prelude.memptr);
+        Ok(())
+    }
+
+    /// Index of the cell `amount` cells right of the data pointer, adding it
+    /// to the tape if needed
+    fn right_of(&mut self, amount: usize) -> usize {
+        if self.config.underflow == UnderflowPolicy::Wrap {
+            return (self.memptr + amount % self.tape.len()) % self.tape.len();
+        }
+        let index = self.memptr + amount;
+        self.tape.grow_right(index);
+        index
+    }
+
+    /// Stop the program with `err`, returning what the callback returns
+    fn fail(&mut self, err: RuntimeError) -> u64 {
+        self.error = Some(err);
+        1
+    }
+}
+
+/// Callback making sure the cell `offset` cells from the data pointer is on
+/// the tape, returning nonzero if the program has to stop
+///
+/// Only called for tapes that don't wrap, when the cell isn't on the tape yet.
+pub(crate) extern "C" fn reach<C: Cell>(state: *mut State<C>, offset: isize, pc: usize) -> u64 {
+    // SAFETY: the compiled code passes on the pointer it was called with
+    let state = unsafe { &mut *state };
+    let index = match state.memptr.checked_add_signed(offset) {
+        Some(index) => index,
+        None => match state.config.underflow {
+            UnderflowPolicy::Grow => {
+                let missing = offset.unsigned_abs() - state.memptr;
+                state.memptr += state.tape.grow_left(missing);
+                state.memptr - offset.unsigned_abs()
+            }
+            _ => {
+                let span = state.spans[pc];
+                return state.fail(RuntimeError::TapeUnderflow { pc, span });
+            }
+        },
+    };
+    state.tape.grow_right(index);
+    state.sync();
+    0
+}
+
+/// Callback reading a byte into the current cell, handling the end of the
+/// input as the [`EofPolicy`] says
+pub(crate) extern "C" fn input<C: Cell>(state: *mut State<C>, pc: usize) -> u64 {
+    // SAFETY: the compiled code passes on the pointer it was called with
+    let state = unsafe { &mut *state };
+    let span = state.spans[pc];
+    if let Err(err) = state.output.flush() {
+        let kind = err.kind();
+        return state.fail(RuntimeError::Io { pc, span, kind });
+    }
+    let mut byte = [0];
+    let value = match (state.input.read_exact(&mut byte), state.config.eof) {
+        (Ok(()), _) => C::from_u64(byte[0].into()),
+        (Err(_), EofPolicy::Zero) => C::default(),
+        (Err(_), EofPolicy::MinusOne) => C::from_u64(u64::MAX),
+        (Err(_), EofPolicy::Unchanged) => return 0,
+        (Err(_), EofPolicy::Error) => {
+            return state.fail(RuntimeError::UnexpectedEof { pc, span });
+        }
+    };
+    state.tape[state.memptr] = value;
+    0
+}
+
+/// Callback writing a cell's value out
+pub(crate) extern "C" fn output<C: Cell>(state: *mut State<C>, value: u64, pc: usize) -> u64 {
+    // SAFETY: the compiled code passes on the pointer it was called with
+    let state = unsafe { &mut *state };
+    match machine::write_cell(state.config.output, &mut *state.output, value) {
+        Ok(()) => 0,
+        Err(err) => {
+            let span = state.spans[pc];
+            let kind = err.kind();
+            state.fail(RuntimeError::Io { pc, span, kind })
+        }
+    }
+}
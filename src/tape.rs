@@ -0,0 +1,76 @@
+use std::ops::{Deref, DerefMut};
+
+use crate::cell::Cell;
+
+/// The memory of a [`Machine`](crate::Machine): a row of cells that can grow in
+/// both directions
+///
+/// Cells are numbered relative to the origin (the cell the data pointer starts
+/// on), so growing the tape to the left never renumbers existing cells. The
+/// tape derefs to its cells from leftmost to rightmost, with cell 0 found at
+/// [`Tape::origin`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tape<C: Cell = u8> {
+    cells: Vec<C>,
+    /// Index in `cells` of cell 0
+    origin: usize,
+}
+
+impl<C: Cell> Tape<C> {
+    /// A tape of `len` zeroed cells, starting at the origin
+    pub(crate) fn new(len: usize) -> Self {
+        let mut cells = Vec::with_capacity(len.max(4096));
+        cells.resize(len, C::default());
+        Tape { cells, origin: 0 }
+    }
+
+    /// Index in the tape's cells of cell 0
+    pub fn origin(&self) -> usize {
+        self.origin
+    }
+
+    /// Value of cell `number`, counting from the origin, or 0 if it hasn't been touched yet
+    pub fn get(&self, number: isize) -> C {
+        self.origin
+            .checked_add_signed(number)
+            .and_then(|index| self.cells.get(index))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Make sure the cell at `index` exists, adding zeroed cells to the right if not
+    pub(crate) fn grow_right(&mut self, index: usize) {
+        if index >= self.cells.len() {
+            self.cells.resize(index + 1, C::default());
+        }
+    }
+
+    /// Add at least `missing` zeroed cells to the left, returning how many were
+    /// added so indices into the tape can be shifted to match
+    ///
+    /// At least as many cells as the tape already has are added each time, so a
+    /// program walking steadily left only pays for copying the tape O(log n) times.
+    pub(crate) fn grow_left(&mut self, missing: usize) -> usize {
+        let added = missing.max(self.cells.len());
+        let mut cells = Vec::with_capacity(added + self.cells.capacity());
+        cells.resize(added, C::default());
+        cells.extend_from_slice(&self.cells);
+        self.cells = cells;
+        self.origin += added;
+        added
+    }
+}
+
+impl<C: Cell> Deref for Tape<C> {
+    type Target = [C];
+
+    fn deref(&self) -> &[C] {
+        &self.cells
+    }
+}
+
+impl<C: Cell> DerefMut for Tape<C> {
+    fn deref_mut(&mut self) -> &mut [C] {
+        &mut self.cells
+    }
+}
@@ -0,0 +1,108 @@
+use std::fmt;
+
+/// A single brainfuck instruction, possibly covering several source characters
+/// once the program has been optimized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Token {
+    /// Move the data pointer by the given amount, right if positive and left if
+    /// negative i.e. '>' and '<'
+    Move(isize),
+    /// Add `value` to the cell `offset` cells away from the data pointer, without
+    /// moving it, e.g. '+' is `Add { offset: 0, value: 1 }` and `>>+++<<` becomes
+    /// `Add { offset: 2, value: 3 }`
+    ///
+    /// The value wraps around at 64 bits, so '-' is adding `u64::MAX`, and it is
+    /// truncated to the cell width when executed, which is still correct for
+    /// every narrower width.
+    Add { offset: isize, value: u64 },
+    /// Set the current cell to the given value, e.g. `[-]` for 0 or `[-]+++` for 3
+    ///
+    /// Like [`Token::Add`] the value wraps around at 64 bits and is truncated to
+    /// the cell width when executed.
+    Set(u64),
+    /// Add the current cell times `factor` to the cell `offset` cells away, e.g.
+    /// `[->++<]` becomes `MulAdd { offset: 1, factor: 2 }` followed by `Set(0)`
+    ///
+    /// Does nothing when the current cell is 0, like the loop it replaces.
+    MulAdd { offset: isize, factor: u64 },
+    /// Move the data pointer right by the given stride until it lands on a zero
+    /// cell, e.g. `[>]` or `[>>>]`
+    ScanRight(usize),
+    /// Move the data pointer left by the given stride until it lands on a zero
+    /// cell, e.g. `[<]` or `[<<<]`
+    ScanLeft(usize),
+    /// Start of a loop i.e. '[', holding the position of the matching ']'
+    Open(usize),
+    /// End of a loop i.e. ']', holding the position of the matching '['
+    Close(usize),
+    /// Read a byte into the current cell i.e. ','
+    Input,
+    /// Write the current cell out as a byte i.e. '.'
+    Output,
+}
+
+/// Assembly-like form of the token, e.g. `add [2] 3` or `open -> 7`
+///
+/// Values are shown signed, so '-' reads as `add -1`.
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Token::Move(amount) => write!(f, "move {amount}"),
+            Token::Add { offset: 0, value } => write!(f, "add {}", value as i64),
+            Token::Add { offset, value } => write!(f, "add [{offset}] {}", value as i64),
+            Token::Set(value) => write!(f, "set {}", value as i64),
+            Token::MulAdd { offset, factor } => write!(f, "muladd [{offset}] {}", factor as i64),
+            Token::ScanRight(stride) => write!(f, "scanr {stride}"),
+            Token::ScanLeft(stride) => write!(f, "scanl {stride}"),
+            Token::Open(pos) => write!(f, "open -> {pos}"),
+            Token::Close(pos) => write!(f, "close -> {pos}"),
+            Token::Input => write!(f, "in"),
+            Token::Output => write!(f, "out"),
+        }
+    }
+}
+
+/// A range of bytes in the original source code, `start` inclusive and `end` exclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+
+    /// The smallest span covering both `self` and `other`
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
+
+    /// The span as a range, for slicing the source it came from
+    pub fn range(self) -> std::ops::Range<usize> {
+        self.start..self.end
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}..{}", self.start, self.end)
+    }
+}
+
+/// A [`Token`] along with the part of the source code it was made from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Instruction {
+    pub token: Token,
+    pub span: Span,
+}
+
+impl Instruction {
+    pub fn new(token: Token, span: Span) -> Self {
+        Instruction { token, span }
+    }
+}
@@ -0,0 +1,411 @@
+//! Compiles programs translated by the backends in `emit`, or with the JIT
+//! and Cranelift, and checks they behave exactly like the interpreter.
+//!
+//! Each backend's test is skipped if its compiler isn't installed.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+#[cfg(any(
+    feature = "cranelift",
+    all(target_arch = "x86_64", target_os = "linux")
+))]
+use proptest::prelude::*;
+use stupidfuck::UnderflowPolicy;
+use stupidfuck::{
+    emit, Cell, Config, EofPolicy, Machine, Optimizer, OutputMode, Program, RuntimeError,
+};
+
+const HELLO: &str = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+
+const ROT13: &str = "-,+[-[>>++++[>++++++++<-]<+<-[>+>+>-[>>>]<[[>+<-]>>+>]<<<<<-]]>>>[-]+>--[-[<->+++[-]]]<[++++++++++++<[>-[>+>>]>[+[<+>-]>+>>]<<<<<-]>>[<+>-]>[-[-<<[-]>>]<<[<<->>-]>>]<<[<<+>>-]]<[-]<.[-]<-,+]";
+
+/// Works out -2 with loops running once per value a cell can have but one, so
+/// it only finishes in time once optimized
+const WIDE: &str = "-[>+<-]>[->++<]>.";
+
+/// Why a run failed, as far as the backend running it can tell
+#[derive(Debug)]
+enum Failure {
+    /// The error the program stopped with
+    Error(RuntimeError),
+    /// What an executable printed to stderr
+    Printed(String),
+}
+
+impl Failure {
+    /// What the executables built by the backends print for the failure
+    fn message(&self) -> String {
+        match self {
+            Failure::Error(err) => format!("error: {err}\n"),
+            Failure::Printed(message) => message.clone(),
+        }
+    }
+}
+
+/// Errors are compared in full where both sides have one, and by what would
+/// be printed for them otherwise
+impl PartialEq for Failure {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Failure::Error(a), Failure::Error(b)) => a == b,
+            _ => self.message() == other.message(),
+        }
+    }
+}
+
+/// Output of a run, and why it failed if it did
+type Outcome = (Vec<u8>, Option<Failure>);
+
+/// A way to run a program other than the interpreter, given a name for any
+/// files it needs
+type Run = fn(&str, &Program, Config, &[u8]) -> Outcome;
+
+/// Outcome of `program` on the interpreter
+fn interpret<C: Cell>(program: &Program, config: Config, input: &[u8]) -> Outcome {
+    let mut output = Vec::new();
+    let result = Machine::<C>::with_config(config).run(program, input, &mut output);
+    (output, result.err().map(Failure::Error))
+}
+
+/// `source` optimized for a fresh machine like the one it would be
+/// interpreted on
+fn optimized<C: Cell>(source: &str, config: Config) -> Program {
+    let mut program = Program::parse(source.as_bytes()).unwrap();
+    Optimizer {
+        fresh_machine: true,
+        ..Optimizer::for_config::<C>(&config)
+    }
+    .run(&mut program);
+    program
+}
+
+/// Run `source` once optimized with `run` and check it against the interpreter
+///
+/// Errors name the instruction that failed, so the interpreter runs the same
+/// optimized program.
+fn check<C: Cell>(backend: &str, name: &str, source: &str, config: Config, input: &[u8], run: Run) {
+    let program = optimized::<C>(source, config);
+    assert_eq!(
+        interpret::<C>(&program, config, input),
+        run(&format!("{name}_{}", C::BITS), &program, config, input),
+        "{name}: {source:?} behaves differently with {backend} for {}-bit cells",
+        C::BITS
+    );
+}
+
+/// Check every case with the backend run by `runs` for 8 and 16-bit cells,
+/// plus a couple of programs with 32 and 64-bit cells
+fn check_cases(backend: &str, [run_8, run_16, run_32, run_64]: [Run; 4]) {
+    for (name, source, config, input) in cases() {
+        check::<u8>(backend, name, source, config, input, run_8);
+        check::<u16>(backend, name, source, config, input, run_16);
+    }
+    check::<u32>(backend, "hello", HELLO, Config::default(), b"", run_32);
+    check::<u64>(backend, "wide", WIDE, Config::default(), b"", run_64);
+}
+
+/// Output of the executable at `path`, and its stderr if it failed
+fn run_native(path: &Path, input: &[u8]) -> Outcome {
+    let mut child = Command::new(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("compiled program starts");
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    let output = child.wait_with_output().unwrap();
+    let failed = (!output.status.success())
+        .then(|| Failure::Printed(String::from_utf8_lossy(&output.stderr).into_owned()));
+    (output.stdout, failed)
+}
+
+/// Directory for the generated sources and executables
+fn work_dir(backend: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(backend);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Whether `compiler` can be run, to skip the tests needing it if not
+fn has(compiler: &str) -> bool {
+    Command::new(compiler)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// Build the executable `name` in the directory for `backend` with the command
+/// `build` gives for its path, then run it
+fn build_and_run(
+    backend: &str,
+    name: &str,
+    input: &[u8],
+    build: impl FnOnce(&Path) -> Command,
+) -> Outcome {
+    let exe_path = work_dir(backend).join(name);
+    let status = build(&exe_path).status().unwrap();
+    assert!(
+        status.success(),
+        "{name}: failed to build {}",
+        exe_path.display()
+    );
+    run_native(&exe_path, input)
+}
+
+/// Translate `program` to C and compile it with `cc`
+fn c<C: Cell>(name: &str, program: &Program, config: Config, input: &[u8]) -> Outcome {
+    build_and_run("c", name, input, |exe_path| {
+        let c_path = exe_path.with_extension("c");
+        std::fs::write(&c_path, emit::c::<C>(program, &config)).unwrap();
+        let mut cc = Command::new("cc");
+        cc.args(["-std=c99", "-O1", "-Wall", "-Werror", "-o"])
+            .arg(exe_path)
+            .arg(c_path);
+        cc
+    })
+}
+
+/// Translate `program` to Rust and compile it with `rustc`
+fn rust<C: Cell>(name: &str, program: &Program, config: Config, input: &[u8]) -> Outcome {
+    build_and_run("rust", name, input, |exe_path| {
+        let rs_path = exe_path.with_extension("rs");
+        std::fs::write(&rs_path, emit::rust::<C>(program, &config)).unwrap();
+        let mut rustc = Command::new("rustc");
+        rustc
+            .args(["--edition=2021", "-D", "warnings", "-o"])
+            .arg(exe_path)
+            .arg(rs_path);
+        rustc
+    })
+}
+
+/// Programs along with the configuration and input they are run with
+fn cases() -> Vec<(&'static str, &'static str, Config, &'static [u8])> {
+    let default = Config::default();
+    let grow = Config {
+        underflow: UnderflowPolicy::Grow,
+        ..default
+    };
+    let wrap = Config {
+        underflow: UnderflowPolicy::Wrap,
+        tape_len: 7,
+        ..default
+    };
+    let unchanged = Config {
+        eof: EofPolicy::Unchanged,
+        ..default
+    };
+    let text = Config {
+        output: OutputMode::Text,
+        ..default
+    };
+    vec![
+        ("hello", HELLO, default, b""),
+        ("echo", ",[.,]", default, b"echo me"),
+        ("rot13", ROT13, unchanged, b"Hello, Rot13!"),
+        ("eof_zero", ",.,.,.", default, b"a"),
+        (
+            "eof_minus_one",
+            "+,.,.",
+            Config {
+                eof: EofPolicy::MinusOne,
+                ..default
+            },
+            b"",
+        ),
+        (
+            "eof_unchanged",
+            "+++,.,.",
+            Config {
+                eof: EofPolicy::Unchanged,
+                ..default
+            },
+            b"b",
+        ),
+        (
+            "eof_error",
+            ",.,.,.",
+            Config {
+                eof: EofPolicy::Error,
+                ..default
+            },
+            b"x",
+        ),
+        ("underflow", "++.>+<<-.", default, b""),
+        ("underflow_scan", "+>+>+,[<]", default, b"z"),
+        ("grow", "+<<+[-<+>]<.>>>>>[-]<<<<<<<<,[.<]", grow, b"abc"),
+        ("wrap", "+<<+[-<+>]<.>>>>>>>>>>+[.>],[.<]", wrap, b"abc"),
+        (
+            "text",
+            "++++++++[>++++++++<-]>[->++++<]>[-<++++>]<+.,.",
+            text,
+            b"x",
+        ),
+        (
+            "far_right",
+            ",>>>>>>>>>>[-]+[>+<[->+<]>]<.",
+            default,
+            b"\x05",
+        ),
+    ]
+}
+
+#[test]
+fn c_backend() {
+    if !has("cc") {
+        eprintln!("skipping: no cc found");
+        return;
+    }
+    check_cases("C", [c::<u8>, c::<u16>, c::<u32>, c::<u64>]);
+}
+
+#[test]
+fn rust_backend() {
+    if !has("rustc") {
+        eprintln!("skipping: no rustc found");
+        return;
+    }
+    check_cases("Rust", [rust::<u8>, rust::<u16>, rust::<u32>, rust::<u64>]);
+}
+
+/// Run `program` with the JIT
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn jit<C: Cell>(_: &str, program: &Program, config: Config, input: &[u8]) -> Outcome {
+    let jit = stupidfuck::Jit::<C>::compile(program, config).unwrap();
+    let mut output = Vec::new();
+    let result = jit.run(input, &mut output);
+    (output, result.err().map(Failure::Error))
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+#[test]
+fn jit_backend() {
+    check_cases("the JIT", [jit::<u8>, jit::<u16>, jit::<u32>, jit::<u64>]);
+}
+
+/// Compile `program` with Cranelift and run it in-process
+#[cfg(feature = "cranelift")]
+fn cranelift<C: Cell>(_: &str, program: &Program, config: Config, input: &[u8]) -> Outcome {
+    let compiled = stupidfuck::cranelift::Compiled::<C>::compile(program, config).unwrap();
+    let mut output = Vec::new();
+    let result = compiled.run(input, &mut output);
+    (output, result.err().map(Failure::Error))
+}
+
+/// Compile `program` to an object file with Cranelift and link it with `cc`
+#[cfg(feature = "cranelift")]
+fn object<C: Cell>(name: &str, program: &Program, config: Config, input: &[u8]) -> Outcome {
+    build_and_run("object", name, input, |exe_path| {
+        let object_path = exe_path.with_extension("o");
+        let object = stupidfuck::cranelift::object(program, C::BITS, &config).unwrap();
+        std::fs::write(&object_path, object).unwrap();
+        let mut cc = Command::new("cc");
+        cc.arg("-o").arg(exe_path).arg(object_path);
+        cc
+    })
+}
+
+#[cfg(feature = "cranelift")]
+#[test]
+fn cranelift_backend() {
+    check_cases(
+        "Cranelift",
+        [
+            cranelift::<u8>,
+            cranelift::<u16>,
+            cranelift::<u32>,
+            cranelift::<u64>,
+        ],
+    );
+    let program = Program::default();
+    assert!(stupidfuck::cranelift::object(&program, 12, &Config::default()).is_err());
+}
+
+#[cfg(feature = "cranelift")]
+#[test]
+fn object_backend() {
+    if !has("cc") {
+        eprintln!("skipping: no cc found");
+        return;
+    }
+    check_cases(
+        "an object file",
+        [object::<u8>, object::<u16>, object::<u32>, object::<u64>],
+    );
+}
+
+/// The backends compiling programs in-process, cheap enough to check on
+/// random programs, with how they run them for 8 and 16-bit cells
+#[cfg(any(
+    feature = "cranelift",
+    all(target_arch = "x86_64", target_os = "linux")
+))]
+fn in_process() -> Vec<(&'static str, Run, Run)> {
+    vec![
+        #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+        ("the JIT", jit::<u8>, jit::<u16>),
+        #[cfg(feature = "cranelift")]
+        ("Cranelift", cranelift::<u8>, cranelift::<u16>),
+    ]
+}
+
+/// Programs made of runs and loops that always stop, some reaching far off
+/// either end of the tape
+#[cfg(any(
+    feature = "cranelift",
+    all(target_arch = "x86_64", target_os = "linux")
+))]
+fn looping() -> impl Strategy<Value = String> {
+    let piece = prop_oneof![
+        (prop::sample::select(vec!['+', '-', '>', '<']), 1..300usize)
+            .prop_map(|(op, count)| op.to_string().repeat(count)),
+        prop::sample::select(vec![
+            ".",
+            ",",
+            "[-]",
+            "[->+<]",
+            "[->>+++<<]",
+            "[-<+>]",
+            "[>]",
+            "[<]",
+            "[>>]",
+            "[<<<]",
+            "[.-]",
+        ])
+        .prop_map(str::to_string),
+    ];
+    prop::collection::vec(piece, 0..16).prop_map(|pieces| pieces.concat())
+}
+
+#[cfg(any(
+    feature = "cranelift",
+    all(target_arch = "x86_64", target_os = "linux")
+))]
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    /// Random programs, both before and after optimizing them, behave the
+    /// same compiled as interpreted
+    #[test]
+    fn compiled_matches_interpreter(
+        source in looping(),
+        input in prop::collection::vec(any::<u8>(), 0..4),
+        underflow in prop::sample::select(vec![UnderflowPolicy::Error, UnderflowPolicy::Wrap, UnderflowPolicy::Grow]),
+        eof in prop::sample::select(vec![EofPolicy::Zero, EofPolicy::MinusOne, EofPolicy::Unchanged, EofPolicy::Error]),
+    ) {
+        // too long for the few cells set to fill it, so scans always stop
+        let config = Config { underflow, eof, tape_len: 100, ..Config::default() };
+        let parsed = Program::parse(source.as_bytes()).unwrap();
+        for (backend, run_8, run_16) in in_process() {
+            for program in [parsed.clone(), optimized::<u8>(&source, config)] {
+                prop_assert_eq!(interpret::<u8>(&program, config, &input), run_8("random", &program, config, &input), "{}", backend);
+            }
+            for program in [parsed.clone(), optimized::<u16>(&source, config)] {
+                prop_assert_eq!(interpret::<u16>(&program, config, &input), run_16("random", &program, config, &input), "{}", backend);
+            }
+        }
+    }
+}
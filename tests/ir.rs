@@ -0,0 +1,94 @@
+//! Checks the disassembly printed by `--emit ir`.
+
+use stupidfuck::{Optimizer, Pass, Program, Token};
+
+#[test]
+fn disassembly() {
+    let mut program = Program::parse(b"+++[->++<]").unwrap();
+    let optimizer = Optimizer {
+        passes: vec![Pass::Merge],
+        ..Optimizer::level(0)
+    };
+    optimizer.run(&mut program);
+    assert_eq!(
+        program.to_string(),
+        "   0  add 3                        0..3
+   1  open -> 6                    3..4
+   2    add -1                     4..5
+   3    move 1                     5..6
+   4    add 2                      6..8
+   5    move -1                    8..9
+   6  close -> 1                   9..10
+"
+    );
+}
+
+#[test]
+fn nested_loops() {
+    let program = Program::parse(b"[[]>[,]]").unwrap();
+    assert_eq!(
+        program.to_string(),
+        "   0  open -> 7                    0..1
+   1    open -> 2                  1..2
+   2    close -> 1                 2..3
+   3    move 1                     3..4
+   4    open -> 6                  4..5
+   5      in                       5..6
+   6    close -> 4                 6..7
+   7  close -> 0                   7..8
+"
+    );
+}
+
+#[test]
+fn tokens() {
+    let cases = [
+        (
+            Token::Add {
+                offset: -2,
+                value: 5,
+            },
+            "add [-2] 5",
+        ),
+        (Token::Set(u64::MAX), "set -1"),
+        (
+            Token::MulAdd {
+                offset: 3,
+                factor: u64::MAX - 1,
+            },
+            "muladd [3] -2",
+        ),
+        (Token::ScanRight(2), "scanr 2"),
+        (Token::ScanLeft(1), "scanl 1"),
+        (Token::Output, "out"),
+    ];
+    for (token, text) in cases {
+        assert_eq!(token.to_string(), text);
+    }
+}
+
+#[test]
+fn after_each_pass() {
+    let mut program = Program::parse(b"+++[-]>>[<]").unwrap();
+    let mut seen = Vec::new();
+    let optimizer = Optimizer {
+        fresh_machine: true,
+        ..Optimizer::default()
+    };
+    optimizer.run_inspect(&mut program, |pass, program| {
+        seen.push((pass, program.instructions().len()));
+    });
+    assert_eq!(
+        seen,
+        [
+            (Pass::Merge, 8),
+            (Pass::Cancel, 8),
+            (Pass::Clear, 6),
+            (Pass::Mul, 6),
+            (Pass::Scan, 4),
+            (Pass::DeadCode, 3),
+            (Pass::Offset, 3),
+            (Pass::Fold, 0),
+        ]
+    );
+}
@@ -0,0 +1,187 @@
+//! Unit and property tests for the run-length merge and cancellation passes.
+
+use proptest::prelude::*;
+use stupidfuck::optimize::{cancel_runs, merge_runs};
+use stupidfuck::{Cell, CellWidth, Config, Instruction, Machine, Program, Span, Token};
+
+/// The instructions of `source` straight from the parser
+fn parse(source: &str) -> Vec<Instruction> {
+    Program::parse(source.as_bytes())
+        .expect("program parses")
+        .instructions()
+        .to_vec()
+}
+
+fn width(bits: u32) -> CellWidth {
+    CellWidth::new(bits).expect("a cell width")
+}
+
+fn merged_tokens(source: &str, cell_bits: u32) -> Vec<Token> {
+    merge_runs(parse(source), width(cell_bits))
+        .into_iter()
+        .map(|inst| inst.token)
+        .collect()
+}
+
+fn cancelled_tokens(source: &str, cell_bits: u32) -> Vec<Token> {
+    cancel_runs(parse(source), width(cell_bits))
+        .into_iter()
+        .map(|inst| inst.token)
+        .collect()
+}
+
+fn add(value: i64) -> Token {
+    Token::Add {
+        offset: 0,
+        value: value as u64,
+    }
+}
+
+#[test]
+fn runs() {
+    assert_eq!(merged_tokens("+++", 8), [add(3)]);
+    assert_eq!(merged_tokens("---", 8), [add(-3)]);
+    assert_eq!(merged_tokens(">>>", 8), [Token::Move(3)]);
+    assert_eq!(merged_tokens(">>><<", 8), [Token::Move(3), Token::Move(-2)]);
+    assert_eq!(merged_tokens("+-+", 8), [add(1), add(-1), add(1)]);
+    assert_eq!(
+        merged_tokens("++>--.", 8),
+        [add(2), Token::Move(1), add(-2), Token::Output]
+    );
+}
+
+#[test]
+fn counts_wrap_at_the_cell_width() {
+    let plus = |count| "+".repeat(count);
+    assert_eq!(merged_tokens(&plus(256), 8), []);
+    assert_eq!(merged_tokens(&plus(257), 8), [add(1)]);
+    assert_eq!(merged_tokens(&plus(255), 8), [add(-1)]);
+    assert_eq!(merged_tokens(&plus(300), 8), [add(44)]);
+    assert_eq!(merged_tokens(&plus(256), 16), [add(256)]);
+    assert_eq!(merged_tokens(&"-".repeat(65536), 16), []);
+    assert_eq!(merged_tokens(&"-".repeat(65537), 16), [add(-1)]);
+    assert_eq!(merged_tokens(&plus(70000), 32), [add(70000)]);
+    assert_eq!(merged_tokens(&plus(70000), 64), [add(70000)]);
+}
+
+#[test]
+fn long_pointer_runs() {
+    let moves = format!("{}{}", ">".repeat(100_000), "<".repeat(40_000));
+    assert_eq!(cancelled_tokens(&moves, 8), [Token::Move(60_000)]);
+    assert_eq!(
+        cancelled_tokens(&format!("{moves}{}", "<".repeat(60_000)), 8),
+        []
+    );
+}
+
+#[test]
+fn cancellation() {
+    assert_eq!(cancelled_tokens("+-", 8), []);
+    assert_eq!(cancelled_tokens("><", 8), []);
+    assert_eq!(cancelled_tokens(">+-<", 8), []);
+    assert_eq!(cancelled_tokens("+>+-<-", 8), []);
+    assert_eq!(cancelled_tokens("++-+", 8), [add(2)]);
+    assert_eq!(cancelled_tokens("+++--", 8), [add(1)]);
+    // brackets are linked again after the merge, so their offsets don't matter
+    assert!(matches!(
+        cancelled_tokens("+[-+]", 8)[..],
+        [Token::Add { value: 1, .. }, Token::Open(_), Token::Close(_)]
+    ));
+}
+
+#[test]
+fn left_excursions_are_kept() {
+    assert_eq!(cancelled_tokens("<>", 8), [Token::Move(-1), Token::Move(1)]);
+    assert_eq!(
+        cancelled_tokens("<<>", 8),
+        [Token::Move(-2), Token::Move(1)]
+    );
+    assert_eq!(cancelled_tokens("><<", 8), [Token::Move(-1)]);
+}
+
+#[test]
+fn spans_cover_the_run() {
+    let merged = merge_runs(parse("+ +\n+>"), CellWidth::of::<u8>());
+    assert_eq!(merged[0].span, Span::new(0, 5));
+    assert_eq!(merged[1].span, Span::new(5, 6));
+}
+
+/// Output of `source` on `input` from a char-by-char interpreter, and whether it
+/// stopped by moving off the start of the tape
+///
+/// Programs are straight-line, so there are no brackets to match.
+fn naive(source: &str, cell_bits: u32, input: &[u8]) -> (Vec<u8>, bool) {
+    let mask = u64::MAX >> (64 - cell_bits);
+    let mut tape = vec![0u64; 1];
+    let mut memptr = 0;
+    let mut input = input.iter();
+    let mut output = Vec::new();
+    for byte in source.bytes() {
+        match byte {
+            b'+' => tape[memptr] = tape[memptr].wrapping_add(1) & mask,
+            b'-' => tape[memptr] = tape[memptr].wrapping_sub(1) & mask,
+            b'>' => {
+                memptr += 1;
+                if memptr == tape.len() {
+                    tape.push(0);
+                }
+            }
+            b'<' => match memptr.checked_sub(1) {
+                Some(left) => memptr = left,
+                None => return (output, true),
+            },
+            b'.' => output.push(tape[memptr] as u8),
+            b',' => tape[memptr] = input.next().copied().map_or(0, u64::from),
+            _ => {}
+        }
+    }
+    (output, false)
+}
+
+/// Output of `source` once merged for the cell width and optimized, and
+/// whether it failed
+fn optimized<C: Cell>(source: &str, config: Config, input: &[u8]) -> (Vec<u8>, bool) {
+    let mut program = Program::parse(source.as_bytes()).expect("program parses");
+    program.optimize_for::<C>();
+    let mut output = Vec::new();
+    let failed = Machine::<C>::with_config(config)
+        .run(&program, input, &mut output)
+        .is_err();
+    (output, failed)
+}
+
+/// Straight-line programs made of runs, some longer than a cell can count
+fn straight_line() -> impl Strategy<Value = String> {
+    let run = (
+        prop::sample::select(vec!['+', '-', '>', '<', '.', ',']),
+        prop_oneof![1..4usize, 250..300usize, 65530..65540usize],
+    );
+    prop::collection::vec(run, 0..12).prop_map(|runs| {
+        runs.into_iter()
+            .map(|(op, count)| match op {
+                '.' | ',' => op.to_string(),
+                _ => op.to_string().repeat(count),
+            })
+            .collect()
+    })
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    #[test]
+    fn matches_naive_interpreter(source in straight_line(), input in prop::collection::vec(any::<u8>(), 0..4)) {
+        let config = Config::default();
+        prop_assert_eq!(naive(&source, 8, &input), optimized::<u8>(&source, config, &input));
+        prop_assert_eq!(naive(&source, 16, &input), optimized::<u16>(&source, config, &input));
+    }
+
+    #[test]
+    fn matches_naive_interpreter_off_the_start(source in straight_line(), input in prop::collection::vec(any::<u8>(), 0..4)) {
+        // start far enough right that long runs of '<' stay on the tape
+        let source = format!("{}{source}", ">".repeat(70_000));
+        let config = Config::default();
+        prop_assert_eq!(naive(&source, 8, &input), optimized::<u8>(&source, config, &input));
+        prop_assert_eq!(naive(&source, 16, &input), optimized::<u16>(&source, config, &input));
+    }
+}
@@ -0,0 +1,637 @@
+//! Checks that optimized programs behave exactly like the unoptimized ones.
+
+use stupidfuck::{
+    Cell, Config, Machine, Optimizer, OutputMode, Pass, Prelude, Program, RuntimeError, Span,
+    Token, UnderflowPolicy,
+};
+
+/// Output, final data pointer and every nonzero cell (by number) after running
+/// `program` on `input`
+///
+/// Cells that were merely visited don't matter, so zeroed ones are left out.
+fn run<C: Cell>(
+    program: &Program,
+    config: Config,
+    input: &[u8],
+) -> (Vec<u8>, isize, Vec<(isize, C)>) {
+    let mut machine = Machine::<C>::with_config(config);
+    let mut output = Vec::new();
+    machine
+        .run(program, input, &mut output)
+        .expect("program runs");
+    let memory = machine.memory();
+    let first = -(memory.origin() as isize);
+    let cells = (first..first + memory.len() as isize)
+        .map(|number| (number, memory.get(number)))
+        .filter(|(_, cell)| !cell.is_zero())
+        .collect();
+    (output, machine.memptr(), cells)
+}
+
+/// An optimizer running every pass for a fresh machine with cells of type `C`
+/// and the given configuration, like the ones [`run`] uses
+fn fresh<C: Cell>(config: &Config) -> Optimizer {
+    Optimizer {
+        fresh_machine: true,
+        ..Optimizer::for_config::<C>(config)
+    }
+}
+
+/// Assert that `source` does the same thing with and without optimizations,
+/// returning the program optimized by every pass but [`Pass::Fold`], which
+/// would leave little of most test programs
+fn assert_equivalent<C: Cell>(source: &str, config: Config, input: &[u8]) -> Program {
+    let plain = Program::parse(source.as_bytes()).expect("program parses");
+    let expected = run::<C>(&plain, config, input);
+    let mut folded = plain.clone();
+    fresh::<C>(&config).run(&mut folded);
+    assert_eq!(
+        expected,
+        run::<C>(&folded, config, input),
+        "folding changed the behaviour of {source:?}"
+    );
+    let mut optimizer = fresh::<C>(&config);
+    optimizer.disable(Pass::Fold);
+    let mut optimized = plain;
+    optimizer.run(&mut optimized);
+    assert_eq!(
+        expected,
+        run::<C>(&optimized, config, input),
+        "optimizing changed the behaviour of {source:?}"
+    );
+    optimized
+}
+
+/// An optimizer running every pass but [`Pass::Fold`] for a fresh machine
+fn unfolded() -> Optimizer {
+    let mut optimizer = fresh::<u64>(&Config::default());
+    optimizer.disable(Pass::Fold);
+    optimizer
+}
+
+/// The tokens `source` is left with after running only `passes` over it
+fn tokens_after(source: &str, passes: &[Pass]) -> Vec<Token> {
+    let mut program = Program::parse(source.as_bytes()).unwrap();
+    Optimizer {
+        passes: passes.to_vec(),
+        ..Optimizer::level(0)
+    }
+    .run(&mut program);
+    program
+        .instructions()
+        .iter()
+        .map(|inst| inst.token)
+        .collect()
+}
+
+fn has_muladd(program: &Program) -> bool {
+    program
+        .instructions()
+        .iter()
+        .any(|inst| matches!(inst.token, Token::MulAdd { .. }))
+}
+
+const HELLO: &str = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+
+#[test]
+fn hello_world() {
+    let program = assert_equivalent::<u8>(HELLO, Config::default(), b"");
+    assert!(has_muladd(&program));
+}
+
+#[test]
+fn clear_loops() {
+    for source in ["[-]", "[+]", "[---]", "[+++++]"] {
+        assert_eq!(
+            tokens_after(source, &[Pass::Merge, Pass::Clear]),
+            [Token::Set(0)],
+            "{source:?} wasn't cleared"
+        );
+    }
+    // even steps loop forever on odd values
+    for source in ["[--]", "[++++]"] {
+        assert!(
+            !tokens_after(source, &[Pass::Merge, Pass::Clear]).contains(&Token::Set(0)),
+            "{source:?} was cleared"
+        );
+    }
+    // additions right after the clear are folded into the value set
+    assert_eq!(tokens_after("[-]+++", &[Pass::Clear]), [Token::Set(3)]);
+    assert_eq!(
+        tokens_after("[-]---", &[Pass::Merge, Pass::Clear]),
+        [Token::Set(u64::MAX - 2)]
+    );
+
+    for source in ["+++[-]++.", "-[+]>+[-]-.<.", ",[-]+.-[--]"] {
+        assert_equivalent::<u8>(source, Config::default(), b"a");
+        assert_equivalent::<u16>(source, Config::default(), b"a");
+    }
+}
+
+#[test]
+fn multiply_loops() {
+    let cases = [
+        "+++++[->+++<]",
+        "+++++[->+>++>+++<<<]",
+        "+++++[>---<-]",
+        "+++++[+>++<]",
+        ">>+++++++[-<+<++>>]",
+        "++++[->+<]>[-<++>>+++<]",
+        "+++[->>+<<]>>[-]++[-<<+>>]",
+    ];
+    for source in cases {
+        let program = assert_equivalent::<u8>(source, Config::default(), b"");
+        assert!(has_muladd(&program), "{source:?} wasn't optimized");
+    }
+}
+
+#[test]
+fn multiply_loops_on_input() {
+    let source = ",[->+>+<<]>[-<+>]>>,[-<++++>]<.<.>>.";
+    for input in [&b"\x00\x00"[..], b"\x01\x02", b"Az", b"\xff\xff"] {
+        assert_equivalent::<u8>(source, Config::default(), input);
+        assert_equivalent::<u16>(source, Config::default(), input);
+    }
+}
+
+#[test]
+fn not_multiply_loops() {
+    // unbalanced, stepping by two, and doing I/O
+    for source in ["+[->+<<]", "++[-->+<]", "+++[->.<]"] {
+        let program = Program::parse(source.as_bytes()).unwrap();
+        let mut optimized = program.clone();
+        unfolded().run(&mut optimized);
+        assert!(!has_muladd(&optimized), "{source:?} was optimized");
+    }
+}
+
+#[test]
+fn multiply_loops_off_the_tape() {
+    let config = Config {
+        underflow: UnderflowPolicy::Grow,
+        ..Config::default()
+    };
+    assert_equivalent::<u8>("+++[-<<+>>]<<[->>>+<<<]", config, b"");
+    let config = Config {
+        underflow: UnderflowPolicy::Wrap,
+        tape_len: 4,
+        ..Config::default()
+    };
+    assert_equivalent::<u8>("+++[-<<+>>]<<[->>>+<<<]>[->>>>+<<<<]", config, b"");
+}
+
+#[test]
+fn multiply_loops_wrapping_onto_themselves() {
+    let config = Config {
+        underflow: UnderflowPolicy::Wrap,
+        tape_len: 3,
+        ..Config::default()
+    };
+    // the cell three to the right is the counter, and one four to the right
+    // is the same as the one to the right
+    let program = assert_equivalent::<u8>("+++[->>>++<<<>+<]>.", config, b"");
+    assert!(!has_muladd(&program));
+    let program = assert_equivalent::<u8>("+++[->+>>>+<<<<]>.", config, b"");
+    assert!(!has_muladd(&program));
+    // adding back to the counter what it takes, this one never stops
+    let mut program = Program::parse(b"+[->>>+<<<]").unwrap();
+    fresh::<u8>(&config).run(&mut program);
+    assert!(!has_muladd(&program));
+    // offsets that don't meet are still fine
+    let program = assert_equivalent::<u8>("+++[->+>++<<]>.", config, b"");
+    assert!(has_muladd(&program));
+}
+
+fn has_scan(program: &Program) -> bool {
+    program
+        .instructions()
+        .iter()
+        .any(|inst| matches!(inst.token, Token::ScanRight(_) | Token::ScanLeft(_)))
+}
+
+#[test]
+fn scan_loops() {
+    let cases = [
+        "+>+>+>+>>+<<<<<[>]>+",
+        "+>+>+>+>+[>]+",
+        "+>>+>>+>>+>>>+[<<]<+",
+        "+>>+>>+>>+[>>]+>+<<<<<<[<<<]",
+        ">>+>+>+[<]>.",
+        "+>+>+>+[>>>]<[<<]",
+    ];
+    for source in cases {
+        let program = assert_equivalent::<u8>(source, Config::default(), b"");
+        assert!(has_scan(&program), "{source:?} wasn't optimized");
+        assert_equivalent::<u32>(source, Config::default(), b"");
+    }
+}
+
+#[test]
+fn scan_loops_off_the_tape() {
+    let config = Config {
+        underflow: UnderflowPolicy::Grow,
+        ..Config::default()
+    };
+    assert_equivalent::<u8>("+>+>+[<]+[<<<]+<<+[<]", config, b"");
+    let config = Config {
+        underflow: UnderflowPolicy::Wrap,
+        tape_len: 7,
+        ..Config::default()
+    };
+    assert_equivalent::<u8>("+>+>+[<]+[<<<]+<<+[>>]>>>>[-]<<<[>>>]", config, b"");
+
+    // scanning off the start fails the same way as stepping off it
+    let source = b"+>+>+[<]";
+    let plain = Program::parse(source).unwrap();
+    let mut optimized = plain.clone();
+    unfolded().run(&mut optimized);
+    assert!(has_scan(&optimized));
+    for program in [plain, optimized] {
+        let result = Machine::<u8>::new().run(&program, &b""[..], Vec::new());
+        assert!(matches!(result, Err(RuntimeError::TapeUnderflow { .. })));
+    }
+}
+
+#[test]
+fn offset_ops() {
+    let source = ">>+++<<-[>>+>-<<<-]>>>>+<<-.>+-<<,>>>>>+++<<<<<[>+<<+>-]";
+    let program = assert_equivalent::<u8>(source, Config::default(), b"x");
+    assert!(program
+        .instructions()
+        .iter()
+        .any(|inst| matches!(inst.token, Token::Add { offset: 2, .. })));
+    assert_equivalent::<u16>(source, Config::default(), b"x");
+
+    let config = Config {
+        underflow: UnderflowPolicy::Grow,
+        ..Config::default()
+    };
+    assert_equivalent::<u8>("+<<<+>>+>-<<<<<[>>++<<-]", config, b"");
+}
+
+#[test]
+fn offset_ops_off_the_tape() {
+    // changing a cell left of the start, or just passing over one
+    for source in [">+<<<+>>", "<>+", "+[<>-]"] {
+        let plain = Program::parse(source.as_bytes()).unwrap();
+        let mut optimized = plain.clone();
+        unfolded().run(&mut optimized);
+        for program in [plain, optimized] {
+            let result = Machine::<u8>::new().run(&program, &b""[..], Vec::new());
+            assert!(
+                matches!(result, Err(RuntimeError::TapeUnderflow { .. })),
+                "{source:?} didn't underflow"
+            );
+        }
+    }
+}
+
+#[test]
+fn cancellation() {
+    for source in [
+        "+-",
+        "-+",
+        "><",
+        "+++---",
+        ">>><<<",
+        "+>-<-+>+<",
+        "[-]>[+]<",
+    ] {
+        let mut program = Program::parse(source.as_bytes()).unwrap();
+        unfolded().run(&mut program);
+        assert!(
+            program.instructions().iter().all(|inst| matches!(
+                inst.token,
+                Token::Set(0) | Token::Add { .. } | Token::Move(_)
+            )),
+            "{source:?} left {:?}",
+            program.instructions()
+        );
+        assert!(
+            program
+                .instructions()
+                .iter()
+                .all(|inst| !matches!(inst.token, Token::Move(0) | Token::Add { value: 0, .. })),
+            "{source:?} left {:?}",
+            program.instructions()
+        );
+    }
+
+    let mut program = Program::parse(b"+-><").unwrap();
+    unfolded().run(&mut program);
+    assert!(program.instructions().is_empty());
+    let mut program = Program::parse(b"+++--").unwrap();
+    unfolded().run(&mut program);
+    let tokens: Vec<Token> = program
+        .instructions()
+        .iter()
+        .map(|inst| inst.token)
+        .collect();
+    assert_eq!(
+        tokens,
+        [Token::Add {
+            offset: 0,
+            value: 1
+        }]
+    );
+}
+
+#[test]
+fn wide_cells() {
+    let source = "++++++++++++++++[->++++++++++++++++<]>[->++++++++++++++++<]>[-<+>>+<]";
+    assert_equivalent::<u8>(source, Config::default(), b"");
+    assert_equivalent::<u16>(source, Config::default(), b"");
+    assert_equivalent::<u32>(source, Config::default(), b"");
+    assert_equivalent::<u64>(source, Config::default(), b"");
+}
+
+#[test]
+fn levels_and_passes() {
+    let sources = [
+        HELLO,
+        "+++++[->+++<]>[>]<<[<]",
+        ">>+++[-<+>]<[-]+++>,[.-]",
+        ">+>+>+<<>>[[-]<]",
+    ];
+    let mut optimizers: Vec<Optimizer> = (0..=3).map(Optimizer::level).collect();
+    for pass in Pass::ALL {
+        optimizers.push(Optimizer {
+            passes: vec![pass],
+            ..Optimizer::level(0)
+        });
+        let mut without = Optimizer::default();
+        without.disable(pass);
+        optimizers.push(without);
+    }
+    for optimizer in optimizers.clone() {
+        optimizers.push(Optimizer {
+            fresh_machine: true,
+            ..optimizer
+        });
+    }
+    for source in sources {
+        let plain = Program::parse(source.as_bytes()).unwrap();
+        for optimizer in &optimizers {
+            let mut optimized = plain.clone();
+            optimizer.run(&mut optimized);
+            assert_eq!(
+                run::<u8>(&plain, Config::default(), b"ab"),
+                run::<u8>(&optimized, Config::default(), b"ab"),
+                "{optimizer:?} changed the behaviour of {source:?}"
+            );
+        }
+    }
+
+    let plain = Program::parse(HELLO.as_bytes()).unwrap();
+    let mut optimized = plain.clone();
+    Optimizer::level(0).run(&mut optimized);
+    assert_eq!(optimized, plain);
+
+    let mut optimizer = Optimizer::level(1);
+    optimizer.enable(Pass::Offset);
+    optimizer.enable(Pass::Mul);
+    optimizer.enable(Pass::Mul);
+    assert_eq!(
+        optimizer.passes,
+        [Pass::Merge, Pass::Cancel, Pass::Mul, Pass::Offset]
+    );
+    optimizer.disable(Pass::Mul);
+    optimizer.run(&mut optimized);
+    assert!(!has_muladd(&optimized));
+}
+
+#[test]
+fn dead_code() {
+    let cases = [
+        ("[comment, with < and > . and - +]++.", 2),
+        ("[[-]]>[<<]+[-][+.]++.", 5),
+        ("+[-]>>[-][[.]]<<.", 3),
+        ("[-]>[-]+[>[-]]<[-]", 8),
+        (">>+<<[->+<]>>[<<]", 3),
+        ("+[>]<[-]>[<]", 5),
+        (",[-]>+<[-]", 3),
+    ];
+    for (source, len) in cases {
+        let program = assert_equivalent::<u8>(source, Config::default(), b"a");
+        assert_eq!(
+            program.instructions().len(),
+            len,
+            "{source:?} left {:?}",
+            program.instructions()
+        );
+    }
+
+    // loops that might run are kept, even when they'd fail
+    for source in ["+>[-]<[<]", ",[>]<[<]", "+[>+<-]>[<<]"] {
+        let mut program = Program::parse(source.as_bytes()).unwrap();
+        unfolded().run(&mut program);
+        assert!(
+            program
+                .instructions()
+                .iter()
+                .any(|inst| matches!(inst.token, Token::ScanLeft(_) | Token::Open(_))),
+            "{source:?} left {:?}",
+            program.instructions()
+        );
+    }
+
+    // with a wrapping tape, cells a tape length apart are the same one
+    let config = Config {
+        underflow: UnderflowPolicy::Wrap,
+        tape_len: 3,
+        ..Config::default()
+    };
+    let program = assert_equivalent::<u8>("+>>>[->+<]>.", config, b"");
+    assert!(has_muladd(&program));
+    assert_equivalent::<u8>("[-]>>>+<<<[>+<-]>.", config, b"");
+}
+
+#[test]
+fn dead_code_empty_tape() {
+    // a wrapping tape of no cells has one, as on the machine
+    let config = Config {
+        underflow: UnderflowPolicy::Wrap,
+        tape_len: 0,
+        ..Config::default()
+    };
+    let plain = Program::parse(b"++.>+.").unwrap();
+    let mut optimized = plain.clone();
+    Optimizer {
+        passes: vec![Pass::DeadCode],
+        tape_len: Some(0),
+        fresh_machine: true,
+        ..Optimizer::level(0)
+    }
+    .run(&mut optimized);
+    assert_eq!(
+        run::<u8>(&plain, config, b""),
+        run::<u8>(&optimized, config, b"")
+    );
+}
+
+/// Output of `second` run on the same machine right after `first`
+fn run_after(first: &Program, second: &Program) -> Vec<u8> {
+    let mut machine = Machine::<u8>::new();
+    let mut output = Vec::new();
+    machine.run(first, &b""[..], &mut output).unwrap();
+    machine.run(second, &b""[..], &mut output).unwrap();
+    output
+}
+
+#[test]
+fn used_machine() {
+    // nothing is known about the cells unless the machine is fresh
+    let first = Program::parse(b"+++").unwrap();
+    let plain = Program::parse(b"[.-]").unwrap();
+    assert_eq!(run_after(&first, &plain), [3, 2, 1]);
+    for pass in [Pass::DeadCode, Pass::Fold] {
+        let mut optimized = plain.clone();
+        Optimizer {
+            passes: vec![pass],
+            ..Optimizer::level(0)
+        }
+        .run(&mut optimized);
+        assert_eq!(run_after(&first, &optimized), [3, 2, 1], "{pass:?}");
+    }
+}
+
+/// `source` optimized by every pass for 8-bit cells, checking it still behaves the same
+fn folded(source: &str, optimizer: &Optimizer, input: &[u8]) -> Program {
+    let plain = Program::parse(source.as_bytes()).unwrap();
+    let mut program = plain.clone();
+    optimizer.run(&mut program);
+    assert_eq!(
+        run::<u8>(&plain, Config::default(), input),
+        run::<u8>(&program, Config::default(), input),
+        "folding changed the behaviour of {source:?}"
+    );
+    program
+}
+
+#[test]
+fn fold() {
+    let optimizer = fresh::<u8>(&Config::default());
+    let program = folded(HELLO, &optimizer, b"");
+    assert!(program.instructions().is_empty());
+    assert_eq!(program.prelude().output, b"Hello World!\n".map(u64::from));
+    assert_eq!(program.prelude().span, Span::new(0, HELLO.len()));
+
+    // stopping at input
+    let program = folded("+++++[>++<-]>.[>,.<-]", &optimizer, b"abcdefghij");
+    assert_eq!(program.prelude().tape, [0, 10]);
+    assert_eq!(program.prelude().memptr, 1);
+    assert_eq!(program.prelude().output, [10]);
+    assert!(matches!(program.instructions()[0].token, Token::Open(_)));
+
+    // stopping inside a loop backs up to the start of the outermost one
+    let source = "++>+<[>[,.]<-]+";
+    let program = folded(source, &optimizer, b"xy");
+    assert_eq!(program.prelude().tape, [2, 1]);
+    assert_eq!(program.instructions().len(), 10);
+    let program = folded(source, &optimizer, b"");
+    assert_eq!(program.prelude().tape, [2, 1]);
+
+    // running out of steps
+    let source = "++[+>+<+]>.";
+    let small = Optimizer {
+        fold_steps: 50,
+        ..optimizer.clone()
+    };
+    let program = folded(source, &small, b"");
+    assert_eq!(program.prelude().tape, [2]);
+    assert!(matches!(program.instructions()[0].token, Token::Open(_)));
+    assert!(folded(source, &optimizer, b"").instructions().is_empty());
+    let program = folded(
+        "+[+]",
+        &Optimizer {
+            fold_steps: 0,
+            ..optimizer.clone()
+        },
+        b"",
+    );
+    assert_eq!(*program.prelude(), Prelude::default());
+}
+
+#[test]
+fn fold_off_the_tape() {
+    // moving off the start is left for the machine to report
+    let source = "++.>+<<-";
+    let plain = Program::parse(source.as_bytes()).unwrap();
+    let mut program = plain.clone();
+    fresh::<u8>(&Config::default()).run(&mut program);
+    assert_eq!(program.prelude().output, [2]);
+    for program in [plain, program] {
+        let mut output = Vec::new();
+        let result = Machine::<u8>::new().run(&program, &b""[..], &mut output);
+        assert_eq!(output, [2]);
+        assert!(matches!(result, Err(RuntimeError::TapeUnderflow { .. })));
+    }
+
+    let configs = [
+        Config {
+            underflow: UnderflowPolicy::Grow,
+            ..Config::default()
+        },
+        Config {
+            underflow: UnderflowPolicy::Wrap,
+            tape_len: 5,
+            ..Config::default()
+        },
+    ];
+    for config in configs {
+        assert_equivalent::<u8>("++[>+<-]<<+>>>>>>>++.[<]", config, b"");
+        assert_equivalent::<u16>("-[>+>>+<<<-]>>>[>>>>>>]+.", config, b"");
+    }
+}
+
+#[test]
+fn fold_empty_tape() {
+    let config = Config {
+        underflow: UnderflowPolicy::Wrap,
+        tape_len: 0,
+        ..Config::default()
+    };
+    // not clamped by `Optimizer::for_config`, so the pass has to do it
+    let mut optimizer = Optimizer {
+        tape_len: Some(0),
+        ..fresh::<u8>(&config)
+    };
+    optimizer.disable(Pass::DeadCode);
+    let plain = Program::parse(b"++.>+.").unwrap();
+    let mut program = plain.clone();
+    optimizer.run(&mut program);
+    assert_eq!(program.prelude().output, [2]);
+    assert_eq!(
+        run::<u8>(&plain, config, b""),
+        run::<u8>(&program, config, b"")
+    );
+    assert_equivalent::<u8>("+>+.[-]<.>>++.", config, b"");
+}
+
+#[test]
+fn fold_text_output() {
+    let config = Config {
+        output: OutputMode::Text,
+        ..Config::default()
+    };
+    let source = "++++++++[>++++++++<-]>[->++++<]>[-<++++>]<+.,.";
+    let program = assert_equivalent::<u16>(source, config, b"x");
+    let mut folded = program.clone();
+    fresh::<u16>(&config).run(&mut folded);
+    assert_eq!(folded.prelude().output, [1025]);
+    assert_eq!(run::<u16>(&folded, config, b"x").0, "Ё\u{78}".as_bytes());
+}
+
+#[test]
+fn optimize_any_width() {
+    // 256 is 0 in a byte, so the loop is skipped on 8-bit cells
+    let source = format!("{}[.[-]]", "+".repeat(256));
+    let plain = Program::parse(source.as_bytes()).unwrap();
+    let mut optimized = plain.clone();
+    optimized.optimize();
+    assert_eq!(
+        run::<u8>(&optimized, Config::default(), b""),
+        run::<u8>(&plain, Config::default(), b"")
+    );
+    assert_eq!(run::<u16>(&optimized, Config::default(), b"").0, [0]);
+}
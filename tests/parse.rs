@@ -0,0 +1,68 @@
+//! Checks bracket matching and the errors for unbalanced brackets.
+
+use stupidfuck::{ParseErrorKind, Program, Token};
+
+fn tokens(program: &Program) -> Vec<Token> {
+    program
+        .instructions()
+        .iter()
+        .map(|inst| inst.token)
+        .collect()
+}
+
+#[test]
+fn brackets() {
+    let program = Program::parse(b"[[]>[,]]").unwrap();
+    assert_eq!(
+        tokens(&program),
+        [
+            Token::Open(7),
+            Token::Open(2),
+            Token::Close(1),
+            Token::Move(1),
+            Token::Open(6),
+            Token::Input,
+            Token::Close(4),
+            Token::Close(0),
+        ]
+    );
+}
+
+#[test]
+fn unbalanced() {
+    let err = Program::parse(b"+[[]\n  ]]").unwrap_err();
+    assert_eq!(err.kind, ParseErrorKind::UnmatchedClose);
+    assert_eq!((err.offset, err.line, err.column), (8, 2, 4));
+    assert_eq!(err.to_string(), "unmatched ']' at line 2, column 4");
+
+    // the first ']' without a partner is reported before any '['
+    let err = Program::parse(b"]][").unwrap_err();
+    assert_eq!((err.kind, err.offset), (ParseErrorKind::UnmatchedClose, 0));
+
+    let err = Program::parse(b"[]\n[[]").unwrap_err();
+    assert_eq!(err.kind, ParseErrorKind::UnmatchedOpen);
+    assert_eq!((err.offset, err.line, err.column), (3, 2, 1));
+    assert_eq!(err.to_string(), "unmatched '[' at line 2, column 1");
+    assert_eq!(
+        err.snippet(b"[]\n[[]"),
+        "  |\n2 | [[]\n  | ^ unmatched '['\n"
+    );
+}
+
+#[test]
+fn large_programs() {
+    // deeply nested and long, which takes ages when every bracket rescans
+    let depth = 500_000;
+    let source = format!("{}{}", "[+".repeat(depth), "-]".repeat(depth));
+    let program = Program::parse(source.as_bytes()).unwrap();
+    let len = program.instructions().len();
+    assert_eq!(program.instructions()[0].token, Token::Open(len - 1));
+    assert_eq!(
+        program.instructions()[2 * depth - 2].token,
+        Token::Open(2 * depth + 1)
+    );
+
+    let source = "[>]".repeat(depth) + "]";
+    let err = Program::parse(source.as_bytes()).unwrap_err();
+    assert_eq!(err.offset, 3 * depth);
+}